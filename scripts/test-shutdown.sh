@@ -0,0 +1,30 @@
+#!/bin/sh
+# 运行 tests/shutdown.rs, 检查 power::shutdown 确实让 QEMU 关机.
+#
+# bootimage 只把 deps 目录中的程序当作测试 (使用 test-args, 并把状态 0 当作失败), 所以先把测试程序复制出来,
+# 当作普通内核运行, 再直接检查 QEMU 的退出状态. -no-reboot 让重启也退出 QEMU, 所以还要检查测试确实走到了关机.
+set -u
+cd "$(dirname "$0")/.."
+
+kernel=$(cargo test --test shutdown --no-run --message-format=json |
+    sed -n 's/.*"executable":"\([^"]*\)".*/\1/p' | tail -n 1)
+if [ -z "$kernel" ]; then
+    echo "failed to build tests/shutdown.rs" >&2
+    exit 1
+fi
+cp "$kernel" target/shutdown-test
+
+timeout 60 bootimage runner target/shutdown-test \
+    -display none -no-reboot -device isa-debug-exit,iobase=0xf4,iosize=0x04 > target/shutdown-test.log
+status=$?
+cat target/shutdown-test.log
+
+if [ "$status" -ne 0 ]; then
+    echo "QEMU exited with status $status" >&2
+    exit 1
+fi
+if ! grep -q "\[powering off\]" target/shutdown-test.log; then
+    echo "QEMU exited before power::shutdown was called" >&2
+    exit 1
+fi
+echo "[ok]"
@@ -0,0 +1,332 @@
+//! ACPI 表: 在 BIOS 区域中找到 RSDP, 通过 RSDT/XSDT 找到其它的表, 并解析 MADT, FADT 和 HPET.
+//!
+//! 所有的表都通过 bootloader 映射的物理内存访问, 所以必须在 `memory::init` 之后调用 `init`.
+//! 解析结果放在堆上, 所以也要在堆初始化之后.
+
+use crate::memory::phys_to_virt;
+use crate::println;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr;
+use x86_64::PhysAddr;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::Madt;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// BIOS 数据区中保存 EBDA 段地址的位置
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+const SDT_HEADER_SIZE: u32 = 36;
+
+static TABLES: OnceCell<AcpiTables> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No valid RSDP in the EBDA or the BIOS area.
+    RsdpNotFound,
+    /// The checksum of the table with this signature is wrong.
+    InvalidChecksum([u8; 4]),
+    /// A table is too short for the fields it must contain.
+    InvalidTable([u8; 4]),
+}
+
+/// The tables found through the RSDP.
+#[derive(Debug)]
+pub struct AcpiTables {
+    /// 0 for ACPI 1.0 (RSDT), 2 or later for ACPI 2.0+ (XSDT).
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// Every table referenced by the RSDT/XSDT.
+    pub tables: Vec<TableInfo>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+}
+
+/// Where a table is and what it is.
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    pub signature: [u8; 4],
+    pub address: PhysAddr,
+    pub length: u32,
+}
+
+/// An ACPI generic address structure, describing a register in memory or
+/// I/O space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    /// `SYSTEM_MEMORY`, `SYSTEM_IO`, ...
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+}
+
+/// Finds and parses the ACPI tables. Does nothing if they were already parsed.
+pub fn init() -> Result<&'static AcpiTables, AcpiError> {
+    if let Some(tables) = TABLES.get() {
+        return Ok(tables);
+    }
+    let tables = parse_tables()?;
+    TABLES.init_once(|| tables);
+    Ok(TABLES.get().unwrap())
+}
+
+/// Returns the tables parsed by `init`.
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.get()
+}
+
+impl AcpiTables {
+    /// Returns the first table with the given signature, e.g. `b"DSDT"`.
+    pub fn find(&self, signature: &[u8; 4]) -> Option<TableInfo> {
+        self.tables
+            .iter()
+            .find(|table| &table.signature == signature)
+            .copied()
+    }
+}
+
+impl TableInfo {
+    /// The whole table, header included.
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe {
+            core::slice::from_raw_parts(phys_to_virt(self.address).as_ptr(), self.length as usize)
+        }
+    }
+}
+
+impl fmt::Display for AcpiTables {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "ACPI revision {}, OEM {}",
+            self.revision,
+            Ascii(&self.oem_id)
+        )?;
+        write!(f, "tables:")?;
+        for table in &self.tables {
+            write!(f, " {}", Ascii(&table.signature))?;
+        }
+        writeln!(f)?;
+        if let Some(madt) = &self.madt {
+            writeln!(f, "{}", madt)?;
+        }
+        if let Some(fadt) = &self.fadt {
+            writeln!(f, "{}", fadt)?;
+        }
+        if let Some(hpet) = &self.hpet {
+            writeln!(f, "{}", hpet)?;
+        }
+        Ok(())
+    }
+}
+
+/// 把签名之类的字节当作 ASCII 打印
+struct Ascii<'a>(&'a [u8]);
+
+impl fmt::Display for Ascii<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &byte in self.0 {
+            let c = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// 一张已经校验过的表, 读取字段时检查是否越界
+#[derive(Debug, Clone, Copy)]
+struct Table {
+    signature: [u8; 4],
+    address: PhysAddr,
+    length: u32,
+}
+
+impl Table {
+    fn load(address: PhysAddr) -> Result<Self, AcpiError> {
+        let signature: [u8; 4] = unsafe { read_phys(address) };
+        let length: u32 = unsafe { read_phys(address + 4u64) };
+        if length < SDT_HEADER_SIZE {
+            return Err(AcpiError::InvalidTable(signature));
+        }
+        if !checksum_ok(address, length as usize) {
+            return Err(AcpiError::InvalidChecksum(signature));
+        }
+        Ok(Table {
+            signature,
+            address,
+            length,
+        })
+    }
+
+    fn info(&self) -> TableInfo {
+        TableInfo {
+            signature: self.signature,
+            address: self.address,
+            length: self.length,
+        }
+    }
+
+    /// 表头之后的数据长度
+    fn body_len(&self) -> u32 {
+        self.length - SDT_HEADER_SIZE
+    }
+
+    fn read<T: Copy>(&self, offset: u32) -> Option<T> {
+        if offset.checked_add(size_of::<T>() as u32)? > self.length {
+            return None;
+        }
+        Some(unsafe { read_phys(self.address + u64::from(offset)) })
+    }
+
+    fn read_generic_address(&self, offset: u32) -> Option<GenericAddress> {
+        Some(GenericAddress {
+            address_space: self.read(offset)?,
+            bit_width: self.read(offset + 1)?,
+            bit_offset: self.read(offset + 2)?,
+            access_size: self.read(offset + 3)?,
+            address: self.read(offset + 4)?,
+        })
+    }
+}
+
+fn parse_tables() -> Result<AcpiTables, AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let revision: u8 = unsafe { read_phys(rsdp + 15u64) };
+    let oem_id: [u8; 6] = unsafe { read_phys(rsdp + 9u64) };
+
+    // ACPI 2.0 以后使用 64 位地址的 XSDT
+    let xsdt_address: u64 = if revision >= 2 {
+        unsafe { read_phys(rsdp + 24u64) }
+    } else {
+        0
+    };
+    let (root, entry_size) = if xsdt_address != 0 {
+        (Table::load(PhysAddr::new(xsdt_address))?, 8)
+    } else {
+        let rsdt_address: u32 = unsafe { read_phys(rsdp + 16u64) };
+        (Table::load(PhysAddr::new(u64::from(rsdt_address)))?, 4)
+    };
+
+    let mut tables = Vec::new();
+    for i in 0..root.body_len() / entry_size {
+        let offset = SDT_HEADER_SIZE + i * entry_size;
+        let address = if entry_size == 8 {
+            root.read::<u64>(offset)
+        } else {
+            root.read::<u32>(offset).map(u64::from)
+        };
+        match address {
+            Some(address) if address != 0 => {
+                if let Some(table) = skip_invalid(Table::load(PhysAddr::new(address))) {
+                    tables.push(table);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let find = |signature: &[u8; 4]| tables.iter().find(|t| &t.signature == signature);
+    let madt = find(b"APIC").and_then(|table| skip_invalid(Madt::parse(table)));
+    let fadt = find(b"FACP").and_then(|table| skip_invalid(Fadt::parse(table)));
+    let hpet = find(b"HPET").and_then(|table| skip_invalid(Hpet::parse(table)));
+
+    let mut infos: Vec<TableInfo> = tables.iter().map(Table::info).collect();
+    // DSDT 不在 RSDT/XSDT 中, 只能通过 FADT 找到
+    if let Some(dsdt) = fadt.as_ref().and_then(|fadt| fadt.dsdt) {
+        if let Some(table) = skip_invalid(Table::load(dsdt)) {
+            infos.push(table.info());
+        }
+    }
+
+    Ok(AcpiTables {
+        revision,
+        oem_id,
+        tables: infos,
+        madt,
+        fadt,
+        hpet,
+    })
+}
+
+/// 一张损坏的表不影响其它的表: 打印出来然后跳过
+fn skip_invalid<T>(result: Result<T, AcpiError>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            println!("ACPI: skipping table: {:?}", err);
+            None
+        }
+    }
+}
+
+/// RSDP 位于 EBDA 的前 1KB 中, 或者 BIOS 只读区域 0xe0000-0xfffff 中, 都对齐到 16 字节
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda_segment: u16 = unsafe { read_phys(PhysAddr::new(EBDA_SEGMENT_PTR)) };
+    let ebda = u64::from(ebda_segment) << 4;
+    let ebda_area = if ebda != 0 { ebda..ebda + 1024 } else { 0..0 };
+
+    ebda_area
+        .step_by(16)
+        .chain((BIOS_AREA_START..BIOS_AREA_END).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&address| is_rsdp(address))
+}
+
+fn is_rsdp(address: PhysAddr) -> bool {
+    let signature: [u8; 8] = unsafe { read_phys(address) };
+    if &signature != RSDP_SIGNATURE || !checksum_ok(address, 20) {
+        return false;
+    }
+    // ACPI 2.0 的 RSDP 更长, 有自己的校验和
+    let revision: u8 = unsafe { read_phys(address + 15u64) };
+    if revision >= 2 {
+        let length: u32 = unsafe { read_phys(address + 20u64) };
+        return length >= 36 && checksum_ok(address, length as usize);
+    }
+    true
+}
+
+/// 所有字节之和的低 8 位必须为 0
+fn checksum_ok(address: PhysAddr, length: usize) -> bool {
+    let bytes: &[u8] =
+        unsafe { core::slice::from_raw_parts(phys_to_virt(address).as_ptr(), length) };
+    checksum(bytes) == 0
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// 表中的字段不一定对齐
+unsafe fn read_phys<T: Copy>(address: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(address).as_ptr())
+}
+
+#[test_case]
+fn test_checksum() {
+    assert_eq!(checksum(&[]), 0);
+    assert_eq!(checksum(&[0x10, 0xf0]), 0);
+    assert_eq!(checksum(&[0xff, 0x02]), 1);
+}
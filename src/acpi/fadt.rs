@@ -0,0 +1,92 @@
+//! FADT (Fixed ACPI Description Table): 电源管理寄存器, DSDT 的地址和重启寄存器.
+
+use super::{AcpiError, GenericAddress, Table};
+use core::fmt;
+use x86_64::PhysAddr;
+
+// Flags 中的第 10 位: RESET_REG 可用
+const RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// Address of the DSDT, which holds the AML code of the system.
+    pub dsdt: Option<PhysAddr>,
+    /// The interrupt line of the SCI (system control interrupt).
+    pub sci_interrupt: u16,
+    /// I/O port for switching between legacy and ACPI mode, 0 if the
+    /// system is always in ACPI mode.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// I/O ports of the PM1 control registers.
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm1_control_length: u8,
+    pub pm_timer_block: u32,
+    /// CMOS register of the RTC century, 0 if there is none.
+    pub century_register: u8,
+    pub boot_architecture_flags: u16,
+    pub flags: u32,
+    /// Register that resets the system when `reset_value` is written to it.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub(super) fn parse(table: &Table) -> Result<Self, AcpiError> {
+        let invalid = AcpiError::InvalidTable(table.signature);
+        // ACPI 1.0 的 FADT 只有 116 字节, 后面的字段都是可选的
+        let read_u8 = |offset| table.read::<u8>(offset).ok_or(invalid);
+        let read_u32 = |offset| table.read::<u32>(offset).ok_or(invalid);
+
+        let flags = read_u32(112)?;
+        let dsdt = match table.read::<u64>(140) {
+            Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+            _ => u64::from(read_u32(40)?),
+        };
+        let reset_register = if flags & RESET_REG_SUPPORTED != 0 {
+            table.read_generic_address(116)
+        } else {
+            None
+        };
+
+        Ok(Fadt {
+            dsdt: if dsdt != 0 {
+                Some(PhysAddr::new(dsdt))
+            } else {
+                None
+            },
+            sci_interrupt: table.read(46).ok_or(invalid)?,
+            smi_command_port: read_u32(48)?,
+            acpi_enable: read_u8(52)?,
+            acpi_disable: read_u8(53)?,
+            pm1a_control_block: read_u32(64)?,
+            pm1b_control_block: read_u32(68)?,
+            pm1_control_length: read_u8(89)?,
+            pm_timer_block: read_u32(76)?,
+            century_register: read_u8(108)?,
+            boot_architecture_flags: table.read(109).ok_or(invalid)?,
+            flags,
+            reset_register,
+            reset_value: table.read(128).unwrap_or(0),
+        })
+    }
+}
+
+impl fmt::Display for Fadt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "FADT: SCI IRQ {}, PM1a control {:#x}, PM timer {:#x}",
+            self.sci_interrupt, self.pm1a_control_block, self.pm_timer_block
+        )?;
+        if let Some(reset) = self.reset_register {
+            write!(
+                f,
+                ", reset register {:#x} (space {})",
+                reset.address, reset.address_space
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! HPET (High Precision Event Timer) 表: 定时器寄存器的地址和能力.
+
+use super::{AcpiError, GenericAddress, Table};
+use core::fmt;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparator_count: u8,
+    pub counter_64bit: bool,
+    /// The HPET can replace the PIT and RTC interrupts.
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    /// Base of the memory mapped registers.
+    pub base_address: GenericAddress,
+    pub number: u8,
+    /// Minimum number of ticks in periodic mode.
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+impl Hpet {
+    pub(super) fn parse(table: &Table) -> Result<Self, AcpiError> {
+        let invalid = AcpiError::InvalidTable(table.signature);
+        let block_id: u32 = table.read(36).ok_or(invalid)?;
+
+        Ok(Hpet {
+            hardware_revision: block_id as u8,
+            comparator_count: ((block_id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            pci_vendor_id: (block_id >> 16) as u16,
+            base_address: table.read_generic_address(40).ok_or(invalid)?,
+            number: table.read(52).ok_or(invalid)?,
+            minimum_tick: table.read(53).ok_or(invalid)?,
+            page_protection: table.read(55).ok_or(invalid)?,
+        })
+    }
+}
+
+impl fmt::Display for Hpet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "HPET: {} comparators at {:#x}{}",
+            self.comparator_count,
+            self.base_address.address,
+            if self.counter_64bit { ", 64-bit" } else { "" }
+        )
+    }
+}
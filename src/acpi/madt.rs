@@ -0,0 +1,174 @@
+//! MADT (Multiple APIC Description Table): CPU 的 local APIC, I/O APIC 和 ISA 中断的重定向.
+
+use super::{AcpiError, Table};
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::PhysAddr;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The system also has 8259 PICs, which must be disabled to use the APICs.
+    pub pcat_compatible: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub interrupt_overrides: Vec<InterruptOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+/// A CPU with a local APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// The CPU can be used right away.
+    pub enabled: bool,
+    /// The CPU is disabled but can be brought online at runtime.
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is not connected to the I/O APIC input of the same number.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    /// 第 0-1 位是极性, 0b11 表示低电平有效
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    /// 第 2-3 位是触发方式, 0b11 表示电平触发
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// The local APIC input an NMI is connected to.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// 0xff means all processors.
+    pub processor_id: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+impl Madt {
+    pub(super) fn parse(table: &Table) -> Result<Self, AcpiError> {
+        let invalid = AcpiError::InvalidTable(table.signature);
+        let local_apic_address: u32 = table.read(36).ok_or(invalid)?;
+        let flags: u32 = table.read(40).ok_or(invalid)?;
+
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(local_apic_address)),
+            pcat_compatible: flags & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            interrupt_overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        // 表头和上面两个字段之后是变长的 entry, 每个 entry 以类型和长度开头
+        let mut offset = 44;
+        while offset + 2 <= table.length {
+            let kind: u8 = table.read(offset).ok_or(invalid)?;
+            let length: u8 = table.read(offset + 1).ok_or(invalid)?;
+            if length < 2 {
+                return Err(invalid);
+            }
+            madt.parse_entry(table, kind, offset).ok_or(invalid)?;
+            offset += u32::from(length);
+        }
+        Ok(madt)
+    }
+
+    fn parse_entry(&mut self, table: &Table, kind: u8, offset: u32) -> Option<()> {
+        match kind {
+            ENTRY_LOCAL_APIC => {
+                let flags: u32 = table.read(offset + 4)?;
+                self.processors.push(Processor {
+                    processor_id: table.read(offset + 2)?,
+                    apic_id: table.read(offset + 3)?,
+                    enabled: flags & 1 != 0,
+                    online_capable: flags & 2 != 0,
+                });
+            }
+            ENTRY_IO_APIC => {
+                let address: u32 = table.read(offset + 4)?;
+                self.io_apics.push(IoApic {
+                    id: table.read(offset + 2)?,
+                    address: PhysAddr::new(u64::from(address)),
+                    gsi_base: table.read(offset + 8)?,
+                });
+            }
+            ENTRY_INTERRUPT_OVERRIDE => {
+                self.interrupt_overrides.push(InterruptOverride {
+                    bus: table.read(offset + 2)?,
+                    irq: table.read(offset + 3)?,
+                    gsi: table.read(offset + 4)?,
+                    flags: table.read(offset + 8)?,
+                });
+            }
+            ENTRY_LOCAL_APIC_NMI => {
+                self.local_apic_nmis.push(LocalApicNmi {
+                    processor_id: table.read(offset + 2)?,
+                    flags: table.read(offset + 3)?,
+                    lint: table.read(offset + 5)?,
+                });
+            }
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                let address: u64 = table.read(offset + 4)?;
+                self.local_apic_address = PhysAddr::new(address);
+            }
+            // 其它类型 (x2APIC 等) 暂时不需要
+            _ => {}
+        }
+        Some(())
+    }
+}
+
+impl fmt::Display for Madt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MADT: local APIC at {:#x}, {} CPUs (APIC IDs",
+            self.local_apic_address.as_u64(),
+            self.processors.len()
+        )?;
+        for processor in &self.processors {
+            write!(f, " {}", processor.apic_id)?;
+        }
+        write!(f, ")")?;
+        for io_apic in &self.io_apics {
+            write!(
+                f,
+                ", I/O APIC {} at {:#x} (GSI {})",
+                io_apic.id,
+                io_apic.address.as_u64(),
+                io_apic.gsi_base
+            )?;
+        }
+        for entry in &self.interrupt_overrides {
+            write!(f, ", IRQ {} -> GSI {}", entry.irq, entry.gsi)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! 基于帧指针 (rbp) 的栈回溯, 用内核 ELF 文件中的符号表 (见 `elf` 模块) 翻译函数名.
+
+use crate::interrupts::page_fault::probe_read;
+use crate::{println, serial_println};
+use bootloader::bootinfo::MemoryRegionType;
+use bootloader::BootInfo;
+use conquer_once::spin::OnceCell;
+use core::arch::asm;
+use core::fmt;
+use x86_64::VirtAddr;
+
+pub mod elf;
+
+pub use elf::SymbolName;
+
+/// Maximum number of frames printed, so a corrupted or recursive stack
+/// still terminates.
+const MAX_FRAMES: usize = 32;
+
+static SYMBOLS: OnceCell<Result<elf::SymbolTable, SymbolTableError>> = OnceCell::uninit();
+
+/// Why the kernel's symbol table is not available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolTableError {
+    /// `init` has not been called.
+    NotInitialized,
+    /// The memory map has no `Kernel` region, so the kernel ELF file is not
+    /// in memory.
+    NoKernelRegion,
+    /// The `Kernel` region holds no ELF file with a `.symtab`, e.g. because
+    /// the kernel was stripped.
+    NoSymbolTable,
+}
+
+impl fmt::Display for SymbolTableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // 符号表不是编译时生成的 (内核的地址只有链接之后才知道), 而是在运行时从 bootloader
+        // 加载的内核文件中读取, 所以这里说明缺少的是哪一部分
+        match self {
+            SymbolTableError::NotInitialized => write!(f, "backtrace::init was not called"),
+            SymbolTableError::NoKernelRegion => write!(
+                f,
+                "the bootloader reported no Kernel memory region holding the kernel ELF file"
+            ),
+            SymbolTableError::NoSymbolTable => {
+                write!(f, "the kernel ELF file has no .symtab section (stripped?)")
+            }
+        }
+    }
+}
+
+/// Loads the symbol table from the kernel ELF file that the bootloader left
+/// in memory. Without it backtraces only show addresses.
+pub fn init(boot_info: &'static BootInfo) -> Result<(), SymbolTableError> {
+    SYMBOLS.init_once(|| load(boot_info));
+    status()
+}
+
+fn load(boot_info: &'static BootInfo) -> Result<elf::SymbolTable, SymbolTableError> {
+    // bootloader 把内核文件标记成一个或几个相邻的 Kernel 区域
+    let mut regions = boot_info
+        .memory_map
+        .iter()
+        .skip_while(|region| region.region_type != MemoryRegionType::Kernel)
+        .take_while(|region| region.region_type == MemoryRegionType::Kernel);
+    let start = regions
+        .next()
+        .ok_or(SymbolTableError::NoKernelRegion)?
+        .range
+        .start_addr();
+    let end = regions.fold(start, |_, region| region.range.end_addr());
+    let file = unsafe {
+        core::slice::from_raw_parts(
+            (boot_info.physical_memory_offset + start) as *const u8,
+            (end.max(start) - start) as usize,
+        )
+    };
+    elf::SymbolTable::parse(file).ok_or(SymbolTableError::NoSymbolTable)
+}
+
+/// Returns whether `init` loaded the kernel's symbol table, and why not
+/// otherwise.
+pub fn status() -> Result<(), SymbolTableError> {
+    match SYMBOLS.get() {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(err)) => Err(*err),
+        None => Err(SymbolTableError::NotInitialized),
+    }
+}
+
+/// Looks up the function containing `addr`, returning its name and the
+/// offset of `addr` into it.
+pub fn symbolize(addr: VirtAddr) -> Option<(SymbolName, u64)> {
+    SYMBOLS.get()?.as_ref().ok()?.lookup(addr.as_u64())
+}
+
+/// Iterator over the return addresses of a chain of stack frames.
+pub struct StackFrames {
+    frame_pointer: u64,
+    depth: usize,
+}
+
+impl StackFrames {
+    /// Walks the frames starting at `frame_pointer`, the value of `rbp` in
+    /// the innermost frame.
+    pub fn new(frame_pointer: u64) -> Self {
+        StackFrames {
+            frame_pointer,
+            depth: 0,
+        }
+    }
+}
+
+impl Iterator for StackFrames {
+    type Item = VirtAddr;
+
+    fn next(&mut self) -> Option<VirtAddr> {
+        let rbp = self.frame_pointer;
+        if rbp == 0 || rbp % 8 != 0 || self.depth == MAX_FRAMES {
+            return None;
+        }
+        // [rbp] 是调用者的 rbp, [rbp + 8] 是返回地址
+        let caller_rbp = read_u64(rbp)?;
+        let return_address = read_u64(rbp.checked_add(8)?)?;
+        if return_address == 0 {
+            return None;
+        }
+        // 栈向下增长, 调用者的帧一定在更高的地址上
+        self.frame_pointer = if caller_rbp > rbp { caller_rbp } else { 0 };
+        self.depth += 1;
+        VirtAddr::try_new(return_address).ok()
+    }
+}
+
+/// Prints a backtrace of the caller to VGA and serial.
+#[inline(never)]
+pub fn print() {
+    print_frames(None, StackFrames::new(frame_pointer()));
+}
+
+/// Prints a backtrace of an interrupted context, e.g. from an exception
+/// handler: `instruction_pointer` comes from the interrupt stack frame and
+/// `frame_pointer` is the interrupted `rbp`.
+pub fn print_from(instruction_pointer: VirtAddr, frame_pointer: u64) {
+    print_frames(Some(instruction_pointer), StackFrames::new(frame_pointer));
+}
+
+/// Returns the `rbp` of the function that was running when the current
+/// exception handler was entered.
+///
+/// Must be called directly from an `extern "x86-interrupt"` handler, whose
+/// prologue saves the interrupted `rbp` at the bottom of its frame.
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> u64 {
+    read_u64(frame_pointer()).unwrap_or(0)
+}
+
+#[inline(always)]
+fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+fn print_frames(instruction_pointer: Option<VirtAddr>, frames: StackFrames) {
+    emit(format_args!("Backtrace:"));
+    if let Err(err) = status() {
+        emit(format_args!("  (no symbol table: {})", err));
+    }
+    if let Some(ip) = instruction_pointer {
+        emit(format_args!("  {}", Frame(ip, ip)));
+    }
+    for return_address in frames {
+        // 返回地址指向 call 的下一条指令, 减一才落在 call 所在的函数里
+        emit(format_args!(
+            "  {}",
+            Frame(return_address, return_address - 1u64)
+        ));
+    }
+}
+
+fn emit(args: fmt::Arguments) {
+    println!("{}", args);
+    serial_println!("{}", args);
+}
+
+/// 打印的地址和用来查符号的地址
+struct Frame(VirtAddr, VirtAddr);
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0.as_u64())?;
+        match symbolize(self.1) {
+            Some((name, offset)) => write!(f, " {}+{:#x}", name, offset + (self.0 - self.1)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn read_u64(addr: u64) -> Option<u64> {
+    let mut value = 0;
+    for i in 0..8 {
+        let byte = probe_read(VirtAddr::try_new(addr.checked_add(i)?).ok()?)?;
+        value |= u64::from(byte) << (i * 8);
+    }
+    Some(value)
+}
+
+#[test_case]
+fn test_walk_frames() {
+    #[inline(never)]
+    fn depth() -> usize {
+        StackFrames::new(frame_pointer()).count()
+    }
+    #[inline(never)]
+    fn nested() -> usize {
+        let depth = depth();
+        volatile::Volatile::new(depth).read() // prevent tail call optimizations
+    }
+    let outer = depth();
+    assert!(outer > 0);
+    assert_eq!(nested(), (outer + 1).min(MAX_FRAMES));
+}
+
+#[test_case]
+fn test_symbolize() {
+    use core::fmt::Write;
+
+    #[inline(never)]
+    fn known() {}
+
+    if let Err(err) = status() {
+        panic!(
+            "kernel symbol table not loaded: {}. The symbols are read from the kernel ELF file \
+             in the bootloader's Kernel regions at runtime, not generated at build time",
+            err
+        );
+    }
+    let address = VirtAddr::new(known as usize as u64);
+    let (name, offset) = symbolize(address).expect("no symbol");
+    assert_eq!(offset, 0);
+    let mut text = crate::PanicMessage {
+        buf: [0; 1024],
+        len: 0,
+    };
+    write!(text, "{}", name).unwrap();
+    assert_eq!(text.as_str(), "blog_os::backtrace::test_symbolize::known");
+}
@@ -0,0 +1,234 @@
+//! 从 bootloader 加载到内存中的内核 ELF 文件里读取 `.symtab`, 以及 Rust 符号名的还原 (demangle).
+//!
+//! bootloader 把整个内核文件放在 `MemoryRegionType::Kernel` 区域中, 它只去掉调试信息,
+//! 保留了符号表, 所以运行时就能查到函数名, 不需要在编译时生成符号表.
+
+use core::convert::TryInto;
+use core::fmt;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+/// The function symbols of an ELF file.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable {
+    symbols: &'static [u8],
+    names: &'static [u8],
+}
+
+impl SymbolTable {
+    /// Finds the symbol table in the ELF file `file`.
+    pub fn parse(file: &'static [u8]) -> Option<Self> {
+        if file.get(..4)? != ELF_MAGIC || *file.get(4)? != ELF_CLASS_64 {
+            return None;
+        }
+        let section_headers = read_u64(file, 0x28)? as usize;
+        let section_count = usize::from(read_u16(file, 0x3c)?);
+        let section = |index: usize| {
+            let offset = section_headers.checked_add(index.checked_mul(SECTION_HEADER_SIZE)?)?;
+            file.get(offset..offset.checked_add(SECTION_HEADER_SIZE)?)
+        };
+        let contents = |header: &[u8]| {
+            let offset = read_u64(header, 24)? as usize;
+            let size = read_u64(header, 32)? as usize;
+            file.get(offset..offset.checked_add(size)?)
+        };
+
+        let symtab = (0..section_count)
+            .filter_map(section)
+            .find(|header| read_u32(header, 4) == Some(SHT_SYMTAB))?;
+        // sh_link 是符号名所在的字符串表
+        let strtab = section(read_u32(symtab, 40)? as usize)?;
+        Some(SymbolTable {
+            symbols: contents(symtab)?,
+            names: contents(strtab)?,
+        })
+    }
+
+    /// Returns the function containing `addr` and the offset of `addr` into
+    /// it.
+    pub fn lookup(&self, addr: u64) -> Option<(SymbolName, u64)> {
+        self.symbols
+            .chunks_exact(SYMBOL_SIZE)
+            .filter(|symbol| symbol[4] & 0xf == STT_FUNC)
+            .find_map(|symbol| {
+                let start = read_u64(symbol, 8)?;
+                let size = read_u64(symbol, 16)?;
+                if addr < start || addr - start >= size.max(1) {
+                    return None;
+                }
+                let name = self.name(read_u32(symbol, 0)? as usize)?;
+                Some((SymbolName(name), addr - start))
+            })
+    }
+
+    fn name(&self, offset: usize) -> Option<&'static str> {
+        let bytes = self.names.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+/// A symbol name, displayed demangled.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolName(&'static str);
+
+impl SymbolName {
+    /// The name as it appears in the symbol table.
+    pub fn mangled(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl fmt::Display for SymbolName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match Demangle::new(self.0) {
+            Some(demangled) => demangled.fmt(f),
+            None => f.write_str(self.0),
+        }
+    }
+}
+
+/// Rust 的 legacy mangling: `_ZN` + 若干个 `<长度><名字>` + `E`, 最后一段是 `h` 加 16 位十六进制的哈希
+struct Demangle<'a> {
+    path: &'a str,
+}
+
+impl<'a> Demangle<'a> {
+    fn new(mangled: &'a str) -> Option<Self> {
+        let path = mangled.strip_prefix("_ZN")?.strip_suffix('E')?;
+        let demangle = Demangle { path };
+        // 先检查一遍格式, 打印时就不会半途失败
+        let mut rest = path;
+        while !rest.is_empty() {
+            let (_, tail) = split_component(rest)?;
+            rest = tail;
+        }
+        Some(demangle)
+    }
+}
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = self.path;
+        let mut first = true;
+        while let Some((component, tail)) = split_component(rest) {
+            rest = tail;
+            if rest.is_empty() && is_hash(component) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_component(f, component)?;
+        }
+        Ok(())
+    }
+}
+
+fn split_component(s: &str) -> Option<(&str, &str)> {
+    let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+    let len: usize = s[..digits].parse().ok()?;
+    let rest = &s[digits..];
+    if rest.len() < len || !rest.is_char_boundary(len) {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+fn is_hash(component: &str) -> bool {
+    component.len() == 17
+        && component.starts_with('h')
+        && component[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// `$LT$` 之类的转义和代替 `::` 的 `..`
+fn write_component(f: &mut fmt::Formatter, component: &str) -> fmt::Result {
+    // 以 `$` 开头的名字前面会加一个 `_`
+    let mut rest = if component.starts_with("_$") {
+        &component[1..]
+    } else {
+        component
+    };
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = tail;
+        } else if rest.starts_with('$') {
+            let end = match rest[1..].find('$') {
+                Some(end) => end + 1,
+                None => return f.write_str(rest),
+            };
+            match unescape(&rest[1..end]) {
+                Some(c) => write!(f, "{}", c)?,
+                None => f.write_str(&rest[..=end])?,
+            }
+            rest = &rest[end + 1..];
+        } else {
+            let end = rest
+                .find(|c| c == '$' || c == '.')
+                .map_or(rest.len(), |end| end.max(1));
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
+
+fn unescape(escape: &str) -> Option<char> {
+    Some(match escape {
+        "SP" => '@',
+        "BP" => '*',
+        "RF" => '&',
+        "LT" => '<',
+        "GT" => '>',
+        "LP" => '(',
+        "RP" => ')',
+        "C" => ',',
+        _ => {
+            let code = u32::from_str_radix(escape.strip_prefix('u')?, 16).ok()?;
+            core::char::from_u32(code)?
+        }
+    })
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+#[test_case]
+fn test_demangle() {
+    use core::fmt::Write;
+
+    let mut text = crate::PanicMessage {
+        buf: [0; 1024],
+        len: 0,
+    };
+    let name = SymbolName(
+        "_ZN4core3ptr46drop_in_place$LT$alloc..vec..Vec$LT$u8$GT$$GT$17h0123456789abcdefE",
+    );
+    write!(text, "{}", name).unwrap();
+    assert_eq!(
+        text.as_str(),
+        "core::ptr::drop_in_place<alloc::vec::Vec<u8>>"
+    );
+}
@@ -0,0 +1,617 @@
+//! GDB 远程串行协议 (remote serial protocol) 的内核端 stub, 默认使用 COM2.
+//!
+//! 调用 `init` 之后, breakpoint 和 debug 异常都会进入 stub, 等待 GDB 的命令.
+//! 用 `gdb` feature 编译时, `kernel_main` 会调用 `init` 并在开机后停在一个断点上.
+//! `.cargo/config.toml` 中的 `run-gdb` 别名用这个 feature 编译, 并把 COM2 接到 TCP 端口 1234:
+//!
+//! ```text
+//! cargo run-gdb    # 即 cargo run --features gdb -- -serial tcp::1234,server,nowait
+//! gdb target/x86_64-blog_os/debug/blog_os -ex "target remote :1234"
+//! ```
+//!
+//! 普通的 `cargo run` 不会打开这个端口. 不用 `cargo run` 时, 给 QEMU 加上
+//! `-serial stdio -serial tcp::1234,server,nowait` 即可.
+//!
+//! 支持读写寄存器 (`g`/`G`/`p`/`P`) 和内存 (`m`/`M`), `int3` 软件断点 (`Z0`/`z0`),
+//! 单步 (`s`, 使用 RFLAGS 中的 TF 位) 和继续运行 (`c`). 内核运行时不会读串口, 所以 GDB
+//! 的 Ctrl-C 没有作用, 可以在代码中调用 `breakpoint` 停下来.
+
+use crate::interrupts::page_fault::{probe_read, probe_write};
+use crate::interrupts::trap::TrapFrame;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::VirtAddr;
+
+/// I/O port base of COM2.
+pub const COM2: u16 = 0x2f8;
+
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+
+const TRAP_FLAG: u64 = 1 << 8;
+const INT3: u8 = 0xcc;
+
+// SIGTRAP
+const STOP_REPLY: &[u8] = b"S05";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CONNECTION: Mutex<Option<Connection>> = Mutex::new(None);
+static STUB: Mutex<Stub> = Mutex::new(Stub::new());
+
+/// Starts the stub on COM2.
+pub fn init() {
+    init_with_port(COM2);
+}
+
+/// Starts the stub on the 16550 UART at the I/O port `base`.
+pub fn init_with_port(base: u16) {
+    let mut port = unsafe { SerialPort::new(base) };
+    port.init();
+    *CONNECTION.lock() = Some(Connection {
+        port,
+        packet: [0; PACKET_SIZE],
+    });
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns whether debug and breakpoint exceptions are handled by the stub.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Stops in the debugger, waiting for GDB to connect if it has not yet.
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Reports the debug or breakpoint exception described by `frame` to GDB and
+/// handles commands until GDB continues or steps.
+pub fn handle_exception(frame: &mut TrapFrame) {
+    let mut connection = CONNECTION.lock();
+    let connection = connection.as_mut().expect("gdb stub not initialized");
+    let mut stub = STUB.lock();
+
+    frame.rflags &= !TRAP_FLAG;
+    // 第一次停下时 GDB 还没有连接, 等它发 `?` 再报告
+    if stub.resumed {
+        connection.send_packet(STOP_REPLY);
+    }
+    loop {
+        let len = connection.receive_packet();
+        let action = stub.handle_packet(frame, &connection.packet[..len]);
+        if action != Action::Resume {
+            connection.send_packet(stub.reply());
+        }
+        match action {
+            Action::Reply => {}
+            Action::Resume => {
+                stub.resumed = true;
+                return;
+            }
+            Action::Detach => {
+                stub.resumed = false;
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Send the reply and wait for the next packet.
+    Reply,
+    /// Return from the exception.
+    Resume,
+    /// Send the reply and return from the exception.
+    Detach,
+}
+
+/// 串口和收到的包
+struct Connection {
+    port: SerialPort,
+    packet: [u8; PACKET_SIZE],
+}
+
+impl Connection {
+    /// 读取一个 `$data#checksum` 格式的包到 `self.packet`, 返回数据的长度
+    fn receive_packet(&mut self) -> usize {
+        loop {
+            // 跳过 ack 和 Ctrl-C 等包以外的字节
+            while self.port.receive() != b'$' {}
+
+            let mut len = 0;
+            let mut checksum = 0u8;
+            let mut overflow = false;
+            loop {
+                let byte = self.port.receive();
+                if byte == b'#' {
+                    break;
+                }
+                if len < PACKET_SIZE {
+                    self.packet[len] = byte;
+                    len += 1;
+                } else {
+                    overflow = true;
+                }
+                checksum = checksum.wrapping_add(byte);
+            }
+            let expected = [self.port.receive(), self.port.receive()];
+
+            if !overflow && parse_hex(&expected) == Some(u64::from(checksum)) {
+                self.port.send(b'+');
+                return len;
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    fn send_packet(&mut self, data: &[u8]) {
+        let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        loop {
+            self.port.send(b'$');
+            for &byte in data {
+                self.port.send(byte);
+            }
+            self.port.send(b'#');
+            self.port.send(HEX_DIGITS[usize::from(checksum >> 4)]);
+            self.port.send(HEX_DIGITS[usize::from(checksum & 0xf)]);
+            // `-` 表示 GDB 收到的数据有误, 需要重发
+            if self.port.receive() != b'-' {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+/// 协议的状态: 插入的断点和要发送的回复
+struct Stub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    // 是否已经回复过 GDB 的 `c`/`s`, 即 GDB 在等待 stop reply
+    resumed: bool,
+    reply: [u8; PACKET_SIZE],
+    reply_len: usize,
+}
+
+impl Stub {
+    const fn new() -> Self {
+        Stub {
+            breakpoints: [None; MAX_BREAKPOINTS],
+            resumed: false,
+            reply: [0; PACKET_SIZE],
+            reply_len: 0,
+        }
+    }
+
+    fn reply(&self) -> &[u8] {
+        &self.reply[..self.reply_len]
+    }
+
+    /// 处理一个命令, 回复写到 `self.reply`. 不支持的命令回复空包.
+    fn handle_packet(&mut self, frame: &mut TrapFrame, packet: &[u8]) -> Action {
+        self.reply_len = 0;
+        let (&command, args) = match packet.split_first() {
+            Some(split) => split,
+            None => return Action::Reply,
+        };
+
+        match command {
+            b'?' => self.reply_str(STOP_REPLY),
+            b'g' => {
+                for index in 0..REGISTER_COUNT {
+                    let (value, size) = read_register(frame, index);
+                    self.reply_hex_le(value, size);
+                }
+            }
+            b'G' => {
+                let mut args = args;
+                for index in 0..REGISTER_COUNT {
+                    let size = register_size(index) * 2;
+                    if args.len() < size {
+                        break;
+                    }
+                    if let Some(value) = parse_hex_le(&args[..size]) {
+                        write_register(frame, index, value);
+                    }
+                    args = &args[size..];
+                }
+                self.reply_str(b"OK");
+            }
+            b'p' => match parse_hex(args) {
+                Some(index) if (index as usize) < REGISTER_COUNT => {
+                    let (value, size) = read_register(frame, index as usize);
+                    self.reply_hex_le(value, size);
+                }
+                _ => self.reply_str(b"E01"),
+            },
+            b'P' => {
+                let parsed = split_once(args, b'=').and_then(|(index, value)| {
+                    Some((parse_hex(index)? as usize, parse_hex_le(value)?))
+                });
+                match parsed {
+                    Some((index, value)) if index < REGISTER_COUNT => {
+                        write_register(frame, index, value);
+                        self.reply_str(b"OK");
+                    }
+                    _ => self.reply_str(b"E01"),
+                }
+            }
+            b'm' => match parse_address_length(args) {
+                // 每个字节回复两个十六进制字符
+                Some((address, length)) if length <= (PACKET_SIZE / 2) as u64 => {
+                    for offset in 0..length {
+                        match read_byte(address.wrapping_add(offset)) {
+                            Some(byte) => self.reply_hex_le(u64::from(byte), 1),
+                            None if offset == 0 => {
+                                self.reply_str(b"E14");
+                                break;
+                            }
+                            None => break,
+                        }
+                    }
+                }
+                _ => self.reply_str(b"E01"),
+            },
+            b'M' => {
+                let parsed = split_once(args, b':')
+                    .and_then(|(range, data)| Some((parse_address_length(range)?, data)));
+                match parsed {
+                    Some(((address, length), data)) if data.len() as u64 == length * 2 => {
+                        let written = data.chunks(2).enumerate().all(|(offset, byte)| {
+                            let byte = parse_hex(byte).unwrap_or(0) as u8;
+                            write_byte(address.wrapping_add(offset as u64), byte)
+                        });
+                        self.reply_str(if written { b"OK" } else { b"E14" });
+                    }
+                    _ => self.reply_str(b"E01"),
+                }
+            }
+            b'c' | b's' => {
+                // 可选的参数是继续执行的地址
+                if let Some(address) = parse_hex(args) {
+                    frame.rip = address;
+                }
+                if command == b's' {
+                    frame.rflags |= TRAP_FLAG;
+                }
+                return Action::Resume;
+            }
+            b'Z' | b'z' => match parse_breakpoint(args) {
+                Some(address) => {
+                    let ok = if command == b'Z' {
+                        self.insert_breakpoint(address)
+                    } else {
+                        self.remove_breakpoint(address)
+                    };
+                    self.reply_str(if ok { b"OK" } else { b"E01" });
+                }
+                // 只支持软件断点, 其它类型回复空包
+                None => {}
+            },
+            b'D' => {
+                self.remove_all_breakpoints();
+                self.reply_str(b"OK");
+                return Action::Detach;
+            }
+            b'H' => self.reply_str(b"OK"),
+            b'q' if args.starts_with(b"Supported") => self.reply_str(b"PacketSize=1000"),
+            b'q' if args == b"Attached" => self.reply_str(b"1"),
+            _ => {}
+        }
+        Action::Reply
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> bool {
+        if self
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|bp| bp.address == address)
+        {
+            return true;
+        }
+        let slot = match self.breakpoints.iter_mut().find(|bp| bp.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let original = match read_byte(address) {
+            Some(byte) => byte,
+            None => return false,
+        };
+        if !write_byte(address, INT3) {
+            return false;
+        }
+        *slot = Some(Breakpoint { address, original });
+        true
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> bool {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(bp) = *slot {
+                if bp.address == address {
+                    *slot = None;
+                    return write_byte(address, bp.original);
+                }
+            }
+        }
+        false
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(bp) = slot.take() {
+                write_byte(bp.address, bp.original);
+            }
+        }
+    }
+
+    fn reply_str(&mut self, s: &[u8]) {
+        let n = s.len().min(PACKET_SIZE - self.reply_len);
+        self.reply[self.reply_len..self.reply_len + n].copy_from_slice(&s[..n]);
+        self.reply_len += n;
+    }
+
+    /// 按小端序输出 `size` 个字节的十六进制
+    fn reply_hex_le(&mut self, value: u64, size: usize) {
+        for i in 0..size {
+            let byte = (value >> (i * 8)) as u8;
+            self.reply_str(&[
+                HEX_DIGITS[usize::from(byte >> 4)],
+                HEX_DIGITS[usize::from(byte & 0xf)],
+            ]);
+        }
+    }
+}
+
+////////////////////////////////////////
+// 寄存器
+////////////////////////////////////////
+
+// GDB 的 amd64 寄存器编号: rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15, rip, eflags,
+// cs, ss, ds, es, fs, gs. 浮点寄存器没有保存, 回复的 `g` 包比 GDB 期望的短, GDB 会把
+// 剩下的寄存器当作不可用.
+const REGISTER_COUNT: usize = 24;
+const RIP: usize = 16;
+
+fn register_size(index: usize) -> usize {
+    if index <= RIP {
+        8
+    } else {
+        4
+    }
+}
+
+fn read_register(frame: &TrapFrame, index: usize) -> (u64, usize) {
+    use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+
+    let value = match index {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        // 异常不会改变数据段寄存器, 直接读当前的值
+        20 => u64::from(DS::get_reg().0),
+        21 => u64::from(ES::get_reg().0),
+        22 => u64::from(FS::get_reg().0),
+        23 => u64::from(GS::get_reg().0),
+        _ => 0,
+    };
+    (value, register_size(index))
+}
+
+/// 段寄存器是只读的, 写入被忽略
+fn write_register(frame: &mut TrapFrame, index: usize, value: u64) {
+    let register = match index {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        _ => return,
+    };
+    *register = value;
+}
+
+////////////////////////////////////////
+// 内存
+////////////////////////////////////////
+
+fn read_byte(address: u64) -> Option<u8> {
+    probe_read(VirtAddr::try_new(address).ok()?)
+}
+
+/// 断点要写到只读的代码段里, 所以写入时暂时关闭 CR0 的写保护
+fn write_byte(address: u64, value: u8) -> bool {
+    let address = match VirtAddr::try_new(address) {
+        Ok(address) => address,
+        Err(_) => return false,
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        let written = probe_write(address, value);
+        Cr0::write(cr0);
+        written
+    })
+}
+
+////////////////////////////////////////
+// 解析
+////////////////////////////////////////
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, &digit| {
+        let digit = (digit as char).to_digit(16)?;
+        Some(value << 4 | u64::from(digit))
+    })
+}
+
+/// 解析小端序的十六进制字节, 例如 "3412" 是 0x1234
+fn parse_hex_le(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() % 2 != 0 || digits.len() > 16 {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .enumerate()
+        .try_fold(0u64, |value, (i, byte)| {
+            Some(value | parse_hex(byte)? << (i * 8))
+        })
+}
+
+fn split_once(s: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = s.iter().position(|&b| b == separator)?;
+    Some((&s[..index], &s[index + 1..]))
+}
+
+/// "addr,length"
+fn parse_address_length(args: &[u8]) -> Option<(u64, u64)> {
+    let (address, length) = split_once(args, b',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+/// "0,addr,kind", 只接受软件断点 (类型 0)
+fn parse_breakpoint(args: &[u8]) -> Option<u64> {
+    let (kind, rest) = split_once(args, b',')?;
+    if kind != b"0" {
+        return None;
+    }
+    let (address, _) = split_once(rest, b',')?;
+    parse_hex(address)
+}
+
+#[cfg(test)]
+fn handle_for_test(
+    stub: &mut Stub,
+    frame: &mut TrapFrame,
+    packet: &str,
+) -> (Action, alloc::string::String) {
+    let action = stub.handle_packet(frame, packet.as_bytes());
+    let reply = alloc::string::String::from_utf8(stub.reply().to_vec()).unwrap();
+    (action, reply)
+}
+
+#[test_case]
+fn test_parse_hex() {
+    assert_eq!(parse_hex(b"1f"), Some(0x1f));
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"xyz"), None);
+    assert_eq!(parse_hex_le(b"3412"), Some(0x1234));
+    assert_eq!(parse_address_length(b"ff00,10"), Some((0xff00, 0x10)));
+    assert_eq!(parse_breakpoint(b"0,1000,1"), Some(0x1000));
+    assert_eq!(parse_breakpoint(b"1,1000,1"), None);
+}
+
+#[test_case]
+fn test_registers() {
+    let mut stub = Stub::new();
+    let mut frame = TrapFrame {
+        rax: 0x1122_3344_5566_7788,
+        rip: 0x20_1000,
+        ..TrapFrame::default()
+    };
+
+    let (_, reply) = handle_for_test(&mut stub, &mut frame, "p0");
+    assert_eq!(reply, "8877665544332211");
+    let (_, reply) = handle_for_test(&mut stub, &mut frame, "g");
+    assert_eq!(reply.len(), (17 * 8 + 7 * 4) * 2);
+    assert_eq!(&reply[16 * 16..17 * 16], "0010200000000000");
+
+    let (_, reply) = handle_for_test(&mut stub, &mut frame, "P10=0020200000000000");
+    assert_eq!(reply, "OK");
+    assert_eq!(frame.rip, 0x20_2000);
+}
+
+#[test_case]
+fn test_memory_and_breakpoints() {
+    static mut TARGET: [u8; 4] = [1, 2, 3, 4];
+
+    let mut stub = Stub::new();
+    let mut frame = TrapFrame::default();
+    let address = unsafe { TARGET.as_ptr() } as u64;
+
+    let read = alloc::format!("m{:x},4", address);
+    let (_, reply) = handle_for_test(&mut stub, &mut frame, &read);
+    assert_eq!(reply, "01020304");
+
+    let write = alloc::format!("M{:x},2:aabb", address);
+    let (_, reply) = handle_for_test(&mut stub, &mut frame, &write);
+    assert_eq!(reply, "OK");
+
+    let insert = alloc::format!("Z0,{:x},1", address + 2);
+    let (_, reply) = handle_for_test(&mut stub, &mut frame, &insert);
+    assert_eq!(reply, "OK");
+    let (_, reply) = handle_for_test(&mut stub, &mut frame, &read);
+    assert_eq!(reply, "aabbcc04");
+
+    let remove = alloc::format!("z0,{:x},1", address + 2);
+    handle_for_test(&mut stub, &mut frame, &remove);
+    let (_, reply) = handle_for_test(&mut stub, &mut frame, &read);
+    assert_eq!(reply, "aabb0304");
+
+    // 未映射的地址
+    let (_, reply) = handle_for_test(&mut stub, &mut frame, "m0,1");
+    assert_eq!(reply, "E14");
+}
+
+#[test_case]
+fn test_step_and_continue() {
+    let mut stub = Stub::new();
+    let mut frame = TrapFrame::default();
+
+    assert_eq!(
+        handle_for_test(&mut stub, &mut frame, "s").0,
+        Action::Resume
+    );
+    assert_ne!(frame.rflags & TRAP_FLAG, 0);
+    assert_eq!(
+        handle_for_test(&mut stub, &mut frame, "c2000").0,
+        Action::Resume
+    );
+    assert_eq!(frame.rip, 0x2000);
+    assert_eq!(
+        handle_for_test(&mut stub, &mut frame, "vMustReplyEmpty"),
+        (Action::Reply, alloc::string::String::new())
+    );
+}
@@ -1,3 +1,6 @@
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ptr;
 use lazy_static::lazy_static;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
@@ -6,61 +9,206 @@ use x86_64::structures::gdt::GlobalDescriptorTable;
 use x86_64::structures::gdt::Descriptor;
 
 pub const DOUBLE_FAULT_STACK_IST_INDEX:u16 = 0;
+pub const NMI_STACK_IST_INDEX:u16 = 1;
+pub const MACHINE_CHECK_STACK_IST_INDEX:u16 = 2;
+// 内核栈溢出时 page fault 也能在可用的栈上处理, 报告为 StackGuard, 而不是变成 double fault
+pub const PAGE_FAULT_STACK_IST_INDEX:u16 = 3;
 
-// 定义 TSS
+/// Number of interrupt stack table entries in use.
+pub const IST_STACK_COUNT:usize = 4;
+
+const IST_STACK_SIZE:usize = 4096 * 5;
+
+// 每个 IST 项一个独立的栈. 同一个 IST 栈上的中断不能嵌套, 否则会覆盖前一个中断的栈帧
+// 这些是 BSP 的栈, 其它 CPU 的栈由 smp 模块分配
+static mut IST_STACKS:[[u8; IST_STACK_SIZE]; IST_STACK_COUNT] = [[0; IST_STACK_SIZE]; IST_STACK_COUNT];
+
+// 从 ring 3 进入中断时 CPU 切换到 TSS 中 privilege_stack_table[0] 指向的栈
+const PRIVILEGE_STACK_SIZE:usize = 4096 * 5;
+static mut PRIVILEGE_STACK:[u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
+
+// 返回 IST 栈的栈顶 (栈向下增长)
+fn ist_stack_end(index:u16) -> VirtAddr {
+    let stack_start = VirtAddr::from_ptr(unsafe { &IST_STACKS[index as usize] });
+    stack_start + IST_STACK_SIZE
+}
+
+// 定义 BSP 的 TSS
+// CPU 在每次切换栈时才读取 TSS, 所以运行时修改其中的栈指针不需要重新加载 TSS
 lazy_static! {
-    static ref TSS:TaskStateSegment = {
+    static ref TSS:TssCell = {
         let mut tss = TaskStateSegment::new();
 
-        tss.interrupt_stack_table[DOUBLE_FAULT_STACK_IST_INDEX as usize] = {
-            const SIZE:usize = 4096 * 5;
-            static mut STACK:[u8; SIZE] = [0;SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + SIZE;
-            stack_end
+        for index in [
+            DOUBLE_FAULT_STACK_IST_INDEX,
+            NMI_STACK_IST_INDEX,
+            MACHINE_CHECK_STACK_IST_INDEX,
+            PAGE_FAULT_STACK_IST_INDEX,
+        ] {
+            tss.interrupt_stack_table[index as usize] = ist_stack_end(index);
+        }
+        tss.privilege_stack_table[0] = {
+            let stack_start = VirtAddr::from_ptr(unsafe { &PRIVILEGE_STACK });
+            stack_start + PRIVILEGE_STACK_SIZE
         };
-        tss
+        TssCell(UnsafeCell::new(tss))
     };
 }
 
+// GDT 中的 TSS 描述符需要 &'static TaskStateSegment, 修改只通过下面的函数进行
+pub(crate) struct TssCell(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for TssCell {}
+
+impl TssCell {
+    fn get(&self) -> *mut TaskStateSegment {
+        self.0.get()
+    }
+}
+
+// 当前 CPU 的 TSS. per-CPU 数据初始化之前只有 BSP 在运行
+fn current_tss() -> &'static TssCell {
+    match crate::percpu::try_current() {
+        Some(cpu) => cpu.tss,
+        None => &TSS,
+    }
+}
+
+pub(crate) fn boot_tss() -> &'static TssCell {
+    &TSS
+}
+
+/// Returns the stack the current CPU switches to when an interrupt raises
+/// the privilege level to ring `index`.
+pub fn privilege_stack(index: usize) -> VirtAddr {
+    // TaskStateSegment 是 packed 的, 不能直接引用其中的字段
+    unsafe { ptr::addr_of!((*current_tss().get()).privilege_stack_table[index]).read_unaligned() }
+}
+
+/// Sets the stack the current CPU switches to when an interrupt raises the
+/// privilege level to ring `index`. Entry 0 is used for interrupts arriving in ring 3,
+/// e.g. it should point to the kernel stack of the thread about to run.
+///
+/// Does not affect the `syscall` entry, see `syscall::set_kernel_stack`.
+///
+/// # Safety
+///
+/// `stack_top` must be the top of a mapped stack that is not in use until it
+/// is replaced again.
+pub unsafe fn set_privilege_stack(index: usize, stack_top: VirtAddr) {
+    assert!(index < 3, "invalid privilege stack index {}", index);
+    // 单条 mov 指令写入, 不会被中断打断
+    ptr::addr_of_mut!((*current_tss().get()).privilege_stack_table[index]).write_unaligned(stack_top);
+}
+
+/// Returns the stack of the current CPU's interrupt stack table entry `index`.
+pub fn interrupt_stack(index: u16) -> VirtAddr {
+    unsafe {
+        ptr::addr_of!((*current_tss().get()).interrupt_stack_table[index as usize]).read_unaligned()
+    }
+}
+
+/// Replaces the stack of the current CPU's interrupt stack table entry
+/// `index`, e.g. one of the `*_STACK_IST_INDEX` entries.
+///
+/// # Safety
+///
+/// `stack_top` must be the top of a mapped stack that is not in use until it
+/// is replaced again, and no interrupt using the entry may be running.
+pub unsafe fn set_interrupt_stack(index: u16, stack_top: VirtAddr) {
+    assert!(index < 7, "invalid interrupt stack table index {}", index);
+    ptr::addr_of_mut!((*current_tss().get()).interrupt_stack_table[index as usize])
+        .write_unaligned(stack_top);
+}
+
 // 定义 GDT
+// 段的顺序是 syscall/sysret 要求的: 内核数据段紧跟内核代码段, 用户代码段紧跟用户数据段 (见 syscall 模块)
+// 每个 CPU 的 GDT 只有 TSS 不同, 所以所有 CPU 的 selector 都相同
+fn new_gdt(tss: &'static TssCell) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let cs_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss.get() }));
+    (gdt, Selectors{cs_selector, kernel_data_selector, user_data_selector, user_code_selector, tss_selector})
+}
+
 lazy_static! {
-    static ref GDT:(GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let cs_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors{cs_selector, tss_selector})
-    };
+    static ref GDT:(GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
 }
 
 
-// 封装代码段selector 和 TSS selector
-struct Selectors {
-    cs_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+// 封装各个段的 selector, 用户段的 selector 的 RPL 是 3
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub cs_selector: SegmentSelector,
+    pub kernel_data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+/// Returns the selectors of the segments in the GDT.
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
 }
 
 // 初始化 GDT
 pub fn init() {
-    
-    use x86_64::instructions::segmentation::set_cs;
-    use x86_64::instructions::tables::load_tss;
-    
-    // 加载 GDT
-    GDT.0.load();
-    
-    // 重新设置 cs, 并加载 tss
-    unsafe {
-        set_cs(GDT.1.cs_selector);
-        load_tss(GDT.1.tss_selector);
-    }
+    load(&GDT);
 }
 
+/// Builds a TSS and GDT for an application processor and loads them on the
+/// current CPU. `interrupt_stacks` holds the stack tops for the
+/// `*_STACK_IST_INDEX` entries, `privilege_stack` the one for ring 3
+/// interrupts.
+pub(crate) fn init_ap(
+    interrupt_stacks: [VirtAddr; IST_STACK_COUNT],
+    privilege_stack: VirtAddr,
+) -> &'static TssCell {
+    let mut tss = TaskStateSegment::new();
+    for (index, &stack_top) in interrupt_stacks.iter().enumerate() {
+        tss.interrupt_stack_table[index] = stack_top;
+    }
+    tss.privilege_stack_table[0] = privilege_stack;
 
+    let tss: &'static TssCell = Box::leak(Box::new(TssCell(UnsafeCell::new(tss))));
+    load(Box::leak(Box::new(new_gdt(tss))));
+    tss
+}
 
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{set_cs, Segment, SS};
+    use x86_64::instructions::tables::load_tss;
 
+    // 加载 GDT
+    gdt.0.load();
 
+    // 重新设置 cs 和 ss, 并加载 tss
+    unsafe {
+        set_cs(gdt.1.cs_selector);
+        SS::set_reg(gdt.1.kernel_data_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+}
 
 
+#[test_case]
+fn test_update_stacks() {
+    const SIZE: usize = 4096;
+    static mut STACK: [u8; SIZE] = [0; SIZE];
+    let stack_top = VirtAddr::from_ptr(unsafe { &STACK }) + SIZE;
 
+    let old = privilege_stack(0);
+    unsafe { set_privilege_stack(0, stack_top) };
+    assert_eq!(privilege_stack(0), stack_top);
+    unsafe { set_privilege_stack(0, old) };
+    assert_eq!(privilege_stack(0), old);
 
+    // 第 6 项没有使用
+    assert_eq!(interrupt_stack(6), VirtAddr::zero());
+    unsafe { set_interrupt_stack(6, stack_top) };
+    assert_eq!(interrupt_stack(6), stack_top);
+    unsafe { set_interrupt_stack(6, VirtAddr::zero()) };
+}
@@ -1,6 +1,11 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::set_general_handler;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
 
+use crate::gdb;
 use crate::gdt;
+use crate::percpu;
 use crate::print;
 use crate::println;
 use crate::task::keyboard::add_scancode;
@@ -8,40 +13,330 @@ use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 
+pub mod apic;
+pub mod page_fault;
+pub mod stats;
+pub mod trap;
+
+use trap::TrapFrame;
+
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
         let mut idt_temp = InterruptDescriptorTable::new();
 
         // 在 IDT 中注册 handler
-        idt_temp.breakpoint.set_handler_fn(breakpoint_handler);
-        idt_temp.page_fault.set_handler_fn(pagefault_handler);
+        idt_temp.divide_error.set_handler_fn(divide_error_handler);
+        idt_temp.overflow.set_handler_fn(overflow_handler);
+        idt_temp.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt_temp.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt_temp.device_not_available.set_handler_fn(device_not_available_handler);
+        idt_temp.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt_temp.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt_temp.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt_temp.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt_temp.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt_temp.alignment_check.set_handler_fn(alignment_check_handler);
+        idt_temp.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt_temp.virtualization.set_handler_fn(virtualization_handler);
+        idt_temp.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+        idt_temp.security_exception.set_handler_fn(security_exception_handler);
         unsafe {
+            // debug 和 breakpoint 需要保存全部通用寄存器, 见 trap 模块
+            idt_temp.debug.set_handler_addr(trap::debug_entry());
+            idt_temp.breakpoint.set_handler_addr(trap::breakpoint_entry());
             idt_temp.double_fault.set_handler_fn(doublefault_handler).set_stack_index(gdt::DOUBLE_FAULT_STACK_IST_INDEX);
+            // NMI 和 machine check 可能发生在任何时候, 不能依赖当前的栈是否可用
+            idt_temp.non_maskable_interrupt.set_handler_fn(nmi_handler).set_stack_index(gdt::NMI_STACK_IST_INDEX);
+            idt_temp.machine_check.set_handler_fn(machine_check_handler).set_stack_index(gdt::MACHINE_CHECK_STACK_IST_INDEX);
+            // 内核栈溢出时 page fault 也要有可用的栈, 才能报告为 StackGuard. 嵌套的 page fault 见 NestedFaultStack
+            idt_temp.page_fault.set_handler_fn(pagefault_handler).set_stack_index(gdt::PAGE_FAULT_STACK_IST_INDEX);
         }
-        idt_temp[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt_temp[InterruptIndex::KeyBoard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        // 16 条 PIC 中断线都使用同一个 handler, 由它分发给 register_irq 注册的 handler
+        set_general_handler!(&mut idt_temp, irq_handler, PIC_1_OFFSET..PIC_1_OFFSET + IRQ_LINES);
+        idt_temp[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic::spurious_interrupt_handler);
         idt_temp
     };
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Error code pushed by the exceptions that refer to a segment selector
+/// (invalid TSS, segment not present, stack segment fault and general
+/// protection fault). An error code of 0 means no selector is involved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode {
+    /// The exception happened while delivering an external event (e.g. an interrupt).
+    pub external: bool,
+    /// The descriptor table the selector index refers to.
+    pub table: DescriptorTable,
+    /// The index of the descriptor in `table`.
+    pub index: u16,
 }
 
-extern "x86-interrupt" fn pagefault_handler(
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl SelectorErrorCode {
+    pub fn new(error_code: u64) -> Self {
+        // bit 0: EXT, bit 1: IDT, bit 2: TI (GDT/LDT), bit 3..16: index
+        let table = match (error_code >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        };
+        SelectorErrorCode {
+            external: error_code & 1 == 1,
+            table,
+            index: ((error_code >> 3) & 0x1fff) as u16,
+        }
+    }
+}
+
+// fault 类型的异常返回后会重新执行出错的指令, 所以除了 trap 类型的异常以外都不能直接返回
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(0);
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+// 由 trap 模块的汇编入口调用, 连接了 gdb 时交给 gdb stub 处理
+fn debug_handler(frame: &mut TrapFrame) {
+    let _stats = stats::enter(1);
+    if gdb::is_enabled() {
+        gdb::handle_exception(frame);
+        return;
+    }
+    println!("EXCEPTION: DEBUG\n{:#?}", frame);
+}
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _stats = stats::enter(2);
+    println!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
+
+fn breakpoint_handler(frame: &mut TrapFrame) {
+    let _stats = stats::enter(3);
+    if gdb::is_enabled() {
+        gdb::handle_exception(frame);
+        return;
+    }
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(4);
+    println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(5);
+    panic!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(6);
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(7);
+    panic!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(10);
+    panic!(
+        "EXCEPTION: INVALID TSS\nError Code: {:?}\n{:#?}",
+        SelectorErrorCode::new(error_code),
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(11);
+    panic!(
+        "EXCEPTION: SEGMENT NOT PRESENT\nError Code: {:?}\n{:#?}",
+        SelectorErrorCode::new(error_code),
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(12);
+    panic!(
+        "EXCEPTION: STACK SEGMENT FAULT\nError Code: {:?}\n{:#?}",
+        SelectorErrorCode::new(error_code),
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(13);
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT\nError Code: {:?}\n{:#?}",
+        SelectorErrorCode::new(error_code),
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(16);
+    panic!("EXCEPTION: x87 FLOATING POINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(17);
+    panic!(
+        "EXCEPTION: ALIGNMENT CHECK\nError Code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let _stats = stats::enter(18);
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(19);
+    panic!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(20);
+    panic!("EXCEPTION: VIRTUALIZATION\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn vmm_communication_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(29);
+    panic!(
+        "EXCEPTION: VMM COMMUNICATION\nError Code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn security_exception_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
+    error_code: u64,
+) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(30);
+    panic!(
+        "EXCEPTION: SECURITY EXCEPTION\nError Code: {:#x}\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn pagefault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
 ) {
-    use crate::hlt_loop;
+    use crate::memory::cow;
+    use page_fault::{PageFault, PageFaultKind};
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", _error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    // 必须在任何可能再次引起 page fault 的代码之前读取 CR2 和切换 IST 项 (需要内核的 GS)
+    let fault = PageFault::new(Cr2::read(), error_code, stack_frame.stack_pointer);
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _nested = NestedFaultStack::enter(&fault, stack_frame.stack_pointer);
+    let _stats = stats::enter(14);
+
+    // 写 copy-on-write 页引起的 page fault, 复制 frame 之后重新执行写入指令即可
+    if fault.kind == PageFaultKind::WriteToReadOnly && cow::handle_write_fault(fault.address) {
+        return;
+    }
+
+    // 异常表中的内核指令出错时, 跳转到它的 fixup 代码继续执行
+    if !fault.user_mode {
+        if let Some(fixup) = page_fault::search_exception_table(stack_frame.instruction_pointer) {
+            unsafe {
+                stack_frame
+                    .as_mut()
+                    .update(|frame| frame.instruction_pointer = fixup);
+            }
+            return;
+        }
+    }
+
+    panic!(
+        "EXCEPTION: PAGE FAULT\nKind: {:?}\nAccessed Address: {:?}\nMode: {}\nError Code: {:?}\n{:#?}",
+        fault.kind,
+        fault.address,
+        if fault.user_mode { "user" } else { "kernel" },
+        error_code,
+        stack_frame
+    );
+}
+
+// 内核栈溢出时, page fault handler 的 panic (包括 backtrace) 只能使用 IST 栈顶部的这么多字节,
+// 下面的部分留给嵌套的 page fault
+const PAGE_FAULT_HANDLER_RESERVE: u64 = 4096 * 3;
+
+/// Moves the page fault IST entry to an unused stack while the page fault
+/// handler runs and restores it on drop.
+///
+/// Without this, a page fault raised by the handler itself (e.g. by
+/// `probe_read` in the backtrace of its panic) would start at the top of the
+/// same IST stack and overwrite the frames of the outer handler.
+struct NestedFaultStack {
+    previous: VirtAddr,
+}
+
+impl NestedFaultStack {
+    fn enter(fault: &page_fault::PageFault, interrupted_stack: VirtAddr) -> Self {
+        use core::arch::asm;
+        use page_fault::PageFaultKind;
+
+        let index = gdt::PAGE_FAULT_STACK_IST_INDEX;
+        let previous = gdt::interrupt_stack(index);
+        let stack_top = if fault.user_mode {
+            // 从 ring 3 进入时当前线程的内核栈是空的
+            gdt::privilege_stack(0)
+        } else if fault.kind != PageFaultKind::StackGuard {
+            // 被打断的代码在 handler 返回之前不会使用栈指针下面的部分 (内核没有 red zone)
+            interrupted_stack
+        } else {
+            let rsp: u64;
+            unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+            VirtAddr::new(rsp - PAGE_FAULT_HANDLER_RESERVE)
+        };
+        // handler 以关中断的方式进入, 不会在这里被同一个 CPU 上的其它 page fault 打断
+        unsafe { gdt::set_interrupt_stack(index, stack_top.align_down(16u64)) };
+        NestedFaultStack { previous }
+    }
+}
+
+impl Drop for NestedFaultStack {
+    fn drop(&mut self) {
+        unsafe { gdt::set_interrupt_stack(gdt::PAGE_FAULT_STACK_IST_INDEX, self.previous) };
+    }
 }
 
 // double fault 的 handler
@@ -49,6 +344,12 @@ extern "x86-interrupt" fn doublefault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    let _stats = stats::enter(8);
+    // 出错的位置, 而不是 panic handler 所在的栈
+    crate::backtrace::print_from(
+        stack_frame.instruction_pointer,
+        crate::backtrace::interrupted_frame_pointer(),
+    );
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -64,6 +365,7 @@ pub static PICS: spin::Mutex<ChainedPics> =
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     KeyBoard,
+    Rtc = PIC_1_OFFSET + 8,
 }
 
 impl InterruptIndex {
@@ -74,21 +376,273 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// The PIC line the interrupt arrives on.
+    pub fn irq_line(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+////////////////////////////////////////
+// IRQ 分发
+////////////////////////////////////////
+
+/// Number of interrupt lines of the two chained PICs.
+pub const IRQ_LINES: u8 = 16;
+
+/// The line the slave PIC is cascaded on; it never carries a device interrupt.
+const CASCADE_LINE: u8 = 2;
+
+/// How many handlers can share one interrupt line.
+pub const MAX_HANDLERS_PER_LINE: usize = 4;
+
+/// A device interrupt handler registered with `register_irq`.
+///
+/// It runs with interrupts disabled; the end of interrupt is sent after all
+/// handlers of the line have run.
+pub type IrqHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line is not one of the 16 PIC lines (or is the cascade line).
+    InvalidLine(u8),
+    /// The line already has `MAX_HANDLERS_PER_LINE` handlers.
+    LineFull(u8),
+}
+
+// 中断分发表, 不使用堆, 因为 init() 在初始化堆之前就会注册 timer 和键盘的 handler
+static IRQ_HANDLERS: spin::Mutex<[[Option<IrqHandler>; MAX_HANDLERS_PER_LINE]; IRQ_LINES as usize]> =
+    spin::Mutex::new([[None; MAX_HANDLERS_PER_LINE]; IRQ_LINES as usize]);
+
+/// Registers `handler` for the PIC interrupt line `line` and unmasks the line.
+///
+/// Several handlers can share a line; all of them are called on every
+/// interrupt of the line, in registration order.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    use x86_64::instructions::interrupts;
+
+    if line >= IRQ_LINES || line == CASCADE_LINE {
+        return Err(IrqError::InvalidLine(line));
+    }
+
+    // 关闭中断, 否则持有锁的时候发生中断会死锁
+    interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = handlers[line as usize]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::LineFull(line))?;
+        *slot = Some(handler);
+        Ok(())
+    })?;
+
+    unmask_irq(line);
+    Ok(())
+}
+
+/// Removes a handler registered with `register_irq`, masking the line when
+/// no handler is left. Returns whether the handler was registered.
+pub fn unregister_irq(line: u8, handler: IrqHandler) -> bool {
+    use x86_64::instructions::interrupts;
+
+    // cascade 线上不会有 handler, 而且屏蔽它会挡住从 PIC 的全部中断
+    if line >= IRQ_LINES || line == CASCADE_LINE {
+        return false;
+    }
+
+    let (found, empty) = interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let line_handlers = &mut handlers[line as usize];
+        let found = match line_handlers
+            .iter()
+            .position(|slot| *slot == Some(handler))
+        {
+            Some(index) => {
+                // 保持注册顺序, 后面的 handler 依次前移
+                line_handlers[index..].rotate_left(1);
+                line_handlers[MAX_HANDLERS_PER_LINE - 1] = None;
+                true
+            }
+            None => false,
+        };
+        (found, line_handlers[0].is_none())
+    });
+
+    if empty {
+        mask_irq(line);
+    }
+    found
+}
+
+/// Masks the interrupt line `line` in the active interrupt controller, so it
+/// no longer raises interrupts.
+pub fn mask_irq(line: u8) {
+    set_irq_mask(line, true);
+}
+
+/// Unmasks the interrupt line `line` in the active interrupt controller.
+pub fn unmask_irq(line: u8) {
+    set_irq_mask(line, false);
+}
+
+/// Returns whether the interrupt line `line` is masked in the active
+/// interrupt controller.
+pub fn is_irq_masked(line: u8) -> bool {
+    if apic::is_active() {
+        return apic::is_irq_masked(line);
+    }
+
+    let mut port = irq_mask_port(line);
+    let mask: u8 = unsafe { port.read() };
+    mask & (1 << (line % 8)) != 0
+}
+
+// 每个 PIC 的数据端口就是它的中断屏蔽寄存器 (IMR)
+fn irq_mask_port(line: u8) -> x86_64::instructions::port::Port<u8> {
+    use x86_64::instructions::port::Port;
+
+    assert!(line < IRQ_LINES, "invalid IRQ line {}", line);
+    if line < 8 {
+        Port::new(0x21)
+    } else {
+        Port::new(0xa1)
+    }
+}
+
+fn set_irq_mask(line: u8, masked: bool) {
+    use x86_64::instructions::interrupts;
+
+    if apic::is_active() {
+        interrupts::without_interrupts(|| apic::set_irq_mask(line, masked));
+        return;
+    }
+
+    let mut port = irq_mask_port(line);
+    let bit = 1 << (line % 8);
+    // 读-改-写 IMR, 这期间不能被打断; 同时持有 PICS 的锁, 与 EOI 互斥
+    interrupts::without_interrupts(|| {
+        let _pics = PICS.lock();
+        unsafe {
+            let mask: u8 = port.read();
+            port.write(if masked { mask | bit } else { mask & !bit });
+        }
+    });
+}
+
+/// Initializes the PICs with every line masked except the cascade line and
+/// registers the keyboard handler. The timer is set up by `time::init`.
+pub fn init_pics() {
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        // 只打开级联线, 其他中断线在注册 handler 的时候才打开
+        let mut master = x86_64::instructions::port::Port::<u8>::new(0x21);
+        let mut slave = x86_64::instructions::port::Port::<u8>::new(0xa1);
+        master.write(!(1 << CASCADE_LINE));
+        slave.write(0xff);
+    }
+
+    register_irq(InterruptIndex::KeyBoard.irq_line(), keyboard_interrupt_handler)
+        .expect("registering keyboard handler failed");
+}
+
+/// 屏蔽两个 PIC 的所有中断线, 切换到 APIC 时使用
+fn disable_pics() {
+    use x86_64::instructions::port::Port;
+
+    let _pics = PICS.lock();
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+/// Signals the end of interrupt `vector` to the active interrupt controller.
+fn end_of_interrupt(vector: u8) {
+    if apic::is_active() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
+
+// 所有 PIC 中断线的入口, 由 set_general_handler! 为每个向量生成一个 x86-interrupt 函数调用它
+fn irq_handler(stack_frame: InterruptStackFrame, index: u8, _error_code: Option<u64>) {
+    let _gs = percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = stats::enter(index);
+    let line = index - PIC_1_OFFSET;
+
+    if !apic::is_active() && is_spurious_irq(line) {
+        handle_spurious_irq(line);
+        return;
+    }
+
+    // 复制一份再调用, 这样 handler 中也可以注册或注销 handler
+    let handlers = IRQ_HANDLERS.lock()[line as usize];
+    for handler in handlers.iter().flatten() {
+        handler();
+    }
+
+    end_of_interrupt(index);
+}
+
+////////////////////////////////////////
+// 8259 的伪中断
+////////////////////////////////////////
+
+// PIC 在中断请求消失得太早时 (例如噪声) 仍然要给 CPU 一个向量, 这时它发送最低优先级的
+// IRQ 7 (主片) 或 IRQ 15 (从片), 但不会在 ISR (In-Service Register) 中置位.
+const MASTER_SPURIOUS_LINE: u8 = 7;
+const SLAVE_SPURIOUS_LINE: u8 = 15;
+
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns how many spurious IRQ 7/15 the PICs have raised so far.
+pub fn spurious_irq_count() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
 }
 
-// Our timer_interrupt_handler has the same signature as our exception handlers, because the CPU reacts identically to exceptions and external interrupts
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+/// Reads the In-Service Registers of both PICs, master in the low byte.
+fn read_pic_isr() -> u16 {
+    use x86_64::instructions::port::Port;
+
+    // OCW3: 下一次读命令端口时返回 ISR
+    const OCW3_READ_ISR: u8 = 0x0b;
 
+    let _pics = PICS.lock();
+    let mut master: Port<u8> = Port::new(0x20);
+    let mut slave: Port<u8> = Port::new(0xa0);
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        master.write(OCW3_READ_ISR);
+        slave.write(OCW3_READ_ISR);
+        (u16::from(slave.read()) << 8) | u16::from(master.read())
+    }
+}
+
+fn is_spurious_irq(line: u8) -> bool {
+    match line {
+        MASTER_SPURIOUS_LINE | SLAVE_SPURIOUS_LINE => read_pic_isr() & (1 << line) == 0,
+        _ => false,
+    }
+}
+
+fn handle_spurious_irq(line: u8) {
+    use x86_64::instructions::port::Port;
+
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+
+    // 主片的伪中断不能发送 EOI, 否则会结束另一个正在处理的中断.
+    // 从片的伪中断只给主片发送 EOI, 因为主片并不知道从片的中断是伪中断.
+    if line == SLAVE_SPURIOUS_LINE {
+        const EOI: u8 = 0x20;
+        let _pics = PICS.lock();
+        unsafe { Port::<u8>::new(0x20).write(EOI) };
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
+fn keyboard_interrupt_handler() {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::<u8>::new(0x60);
@@ -97,18 +651,140 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     add_scancode(scancode);
 
     // print!("push scancode: {:?}", scancode);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::KeyBoard.as_u8());
-    }
 }
 
 pub fn init_idt() {
     IDT.load();
 }
 
+/// Enters the handler of exception `vector` with the stack frame and error
+/// code the CPU pushes when it raises the exception, and returns if the
+/// handler does.
+///
+/// For tests of exceptions that cannot be raised here, e.g. #VC outside of
+/// an SEV-ES guest. `int n` cannot be used for them: it pushes no error
+/// code, so the handler would take the return address for it.
+pub unsafe fn raise_with_error_code(vector: u8, error_code: u64) {
+    use core::arch::asm;
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
+
+    let handler = match vector {
+        10 => invalid_tss_handler as usize,
+        17 => alignment_check_handler as usize,
+        29 => vmm_communication_handler as usize,
+        30 => security_exception_handler as usize,
+        _ => panic!("raise_with_error_code does not support vector {}", vector),
+    };
+    // 和 CPU 一样先把栈对齐到 16 字节, 再压入 ss, rsp, rflags, cs, rip 和错误码.
+    // 进入 interrupt gate 时 CPU 会关中断, iretq 恢复原来的 rflags
+    asm!(
+        "mov {tmp}, rsp",
+        "and rsp, -16",
+        "push {ss}",
+        "push {tmp}",
+        "pushfq",
+        "cli",
+        "push {cs}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "push {error_code}",
+        "jmp {handler}",
+        "2:",
+        tmp = out(reg) _,
+        ss = in(reg) u64::from(SS::get_reg().0),
+        cs = in(reg) u64::from(CS::get_reg().0),
+        error_code = in(reg) error_code,
+        handler = in(reg) handler,
+    );
+}
+
 #[test_case]
 fn test_interrupt_idt() {
     x86_64::instructions::interrupts::int3();
 }
+
+// overflow 和 NMI 的 handler 只打印不 panic, 执行后能返回.
+// into 指令在 64 位模式下无效, 所以 #OF 只能用 int 4 触发
+#[test_case]
+fn test_overflow_and_nmi_return() {
+    use core::arch::asm;
+
+    let overflows = stats::count(4);
+    let nmis = stats::count(2);
+    unsafe {
+        asm!("int 4");
+        asm!("int 2");
+    }
+    assert_eq!(stats::count(4), overflows + 1);
+    assert_eq!(stats::count(2), nmis + 1);
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    let code = SelectorErrorCode::new(0x80);
+    assert_eq!(code.table, DescriptorTable::Gdt);
+    assert_eq!(code.index, 16);
+    assert!(!code.external);
+
+    let code = SelectorErrorCode::new((13 << 3) | 0b011);
+    assert_eq!(code.table, DescriptorTable::Idt);
+    assert_eq!(code.index, 13);
+    assert!(code.external);
+
+    assert_eq!(SelectorErrorCode::new(0b100).table, DescriptorTable::Ldt);
+}
+
+#[test_case]
+fn test_register_irq() {
+    use core::arch::asm;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static FIRST: AtomicUsize = AtomicUsize::new(0);
+    static SECOND: AtomicUsize = AtomicUsize::new(0);
+    fn first() {
+        FIRST.fetch_add(1, Ordering::SeqCst);
+    }
+    fn second() {
+        SECOND.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // IRQ 5 (LPT2) 在 QEMU 中没有设备, 用软件中断 int 37 (PIC_1_OFFSET + 5) 模拟
+    const LINE: u8 = 5;
+    assert!(is_irq_masked(LINE));
+    register_irq(LINE, first).unwrap();
+    register_irq(LINE, second).unwrap();
+    assert!(!is_irq_masked(LINE));
+
+    unsafe { asm!("int 37") };
+    assert_eq!(FIRST.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND.load(Ordering::SeqCst), 1);
+
+    assert!(unregister_irq(LINE, first));
+    unsafe { asm!("int 37") };
+    assert_eq!(FIRST.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND.load(Ordering::SeqCst), 2);
+
+    assert!(unregister_irq(LINE, second));
+    assert!(!unregister_irq(LINE, second));
+    assert!(is_irq_masked(LINE));
+
+    assert_eq!(register_irq(CASCADE_LINE, first), Err(IrqError::InvalidLine(CASCADE_LINE)));
+    assert_eq!(register_irq(IRQ_LINES, first), Err(IrqError::InvalidLine(IRQ_LINES)));
+    let cascade_masked = is_irq_masked(CASCADE_LINE);
+    assert!(!unregister_irq(CASCADE_LINE, first));
+    assert_eq!(is_irq_masked(CASCADE_LINE), cascade_masked);
+    assert!(!unregister_irq(IRQ_LINES, first));
+}
+
+#[test_case]
+fn test_spurious_irq() {
+    use core::arch::asm;
+
+    // 软件中断不会在 ISR 中置位, 所以 PIC 看来它们都是伪中断
+    let count = spurious_irq_count();
+    unsafe {
+        asm!("int 39"); // IRQ 7
+        asm!("int 47"); // IRQ 15
+    }
+    assert_eq!(spurious_irq_count(), count + 2);
+}
@@ -0,0 +1,282 @@
+//! Local APIC 和 I/O APIC 中断控制器.
+//!
+//! 启用 APIC 之后, 8259 PIC 的所有中断线都被屏蔽, 设备中断改由 I/O APIC 转发给 local APIC.
+//! 中断向量保持不变 (ISA IRQ n 仍然使用向量 `PIC_1_OFFSET + n`), 所以 IDT 和 `register_irq`
+//! 的用法都不需要改变, 只是屏蔽中断线和 EOI 交给了 APIC.
+
+use super::{CASCADE_LINE, IRQ_LINES, PIC_1_OFFSET};
+use crate::memory;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Size4KiB};
+use x86_64::PhysAddr;
+
+/// Vector of the local APIC's spurious interrupt; it must not get an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Physical address of the first I/O APIC on PC compatible machines, used
+/// unless the MADT lists another one (see `set_io_apic_address`).
+pub const IO_APIC_PHYS_ADDR: u64 = 0xfec0_0000;
+
+// 把 APIC 的寄存器映射到与堆相同的第四级页表 slot 中, 这样之后创建的 AddressSpace 也能访问
+const LOCAL_APIC_VIRT_ADDR: u64 = 0x_4444_5555_0000;
+const IO_APIC_VIRT_ADDR: u64 = LOCAL_APIC_VIRT_ADDR + 0x1000;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC 寄存器的偏移
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LVT_MASKED: u32 = 1 << 16;
+const SVR_ENABLE: u32 = 1 << 8;
+
+// 处理器间中断 (IPI) 的命令
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+// I/O APIC 通过一个选择寄存器和一个数据窗口间接访问
+const IO_APIC_REGSEL: usize = 0x00;
+const IO_APIC_WINDOW: usize = 0x10;
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static IO_APIC_ADDRESS: AtomicU64 = AtomicU64::new(IO_APIC_PHYS_ADDR);
+
+/// The global system interrupt (I/O APIC pin) of every ISA IRQ.
+///
+/// Identity mapped, except for the PIT on IRQ 0 which is wired to pin 2 on
+/// practically every PC (and QEMU). ACPI's MADT lists the actual overrides.
+/// The cascade line IRQ 2 does not exist on the I/O APIC and is skipped.
+static ISA_IRQ_TO_GSI: Mutex<[u32; IRQ_LINES as usize]> =
+    Mutex::new([2, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+
+/// Polarity and trigger mode bits of the redirection entry of every ISA IRQ.
+/// ISA interrupts are active high and edge triggered, unless the MADT lists
+/// an override.
+static ISA_IRQ_MODE: Mutex<[u32; IRQ_LINES as usize]> = Mutex::new([0; IRQ_LINES as usize]);
+
+/// Returns whether the CPU has a local APIC.
+pub fn is_supported() -> bool {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
+
+/// Returns whether interrupts are currently delivered through the APIC
+/// instead of the 8259 PICs.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Sets the physical address of the I/O APIC that handles the ISA IRQs,
+/// i.e. the one whose first global system interrupt is 0.
+///
+/// Must be called before `init` to take effect.
+pub fn set_io_apic_address(address: PhysAddr) {
+    IO_APIC_ADDRESS.store(address.as_u64(), Ordering::SeqCst);
+}
+
+/// Records that ISA IRQ `irq` is connected to the I/O APIC pin `gsi`, with
+/// the given polarity and trigger mode. IRQs beyond the ISA range are ignored.
+///
+/// Must be called before `init` to take effect.
+pub fn set_isa_irq_override(irq: u8, gsi: u32, active_low: bool, level_triggered: bool) {
+    if irq >= IRQ_LINES {
+        return;
+    }
+    let mut mode = 0;
+    if active_low {
+        mode |= REDIRECTION_ACTIVE_LOW;
+    }
+    if level_triggered {
+        mode |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    ISA_IRQ_TO_GSI.lock()[irq as usize] = gsi;
+    ISA_IRQ_MODE.lock()[irq as usize] = mode;
+}
+
+/// Switches interrupt delivery from the 8259 PICs to the APIC.
+///
+/// Maps the local APIC and I/O APIC registers through `mapper`, masks every
+/// PIC line, enables the local APIC and programs an I/O APIC redirection
+/// entry for each ISA IRQ, keeping the lines that were unmasked in the PIC
+/// (e.g. timer and keyboard) unmasked.
+pub fn init(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), MapToError<Size4KiB>> {
+    use x86_64::instructions::interrupts;
+
+    let apic_base = Msr::new(IA32_APIC_BASE_MSR);
+    let local_apic_phys = unsafe { apic_base.read() } & 0x000f_ffff_ffff_f000;
+    memory::map_mmio(mapper, local_apic_phys, LOCAL_APIC_VIRT_ADDR)?;
+    memory::map_mmio(mapper, IO_APIC_ADDRESS.load(Ordering::SeqCst), IO_APIC_VIRT_ADDR)?;
+
+    interrupts::without_interrupts(|| unsafe {
+        // 先记下在 PIC 中打开的中断线, 再屏蔽整个 PIC
+        let mut unmasked = [false; IRQ_LINES as usize];
+        for line in 0..IRQ_LINES {
+            unmasked[line as usize] = !super::is_irq_masked(line);
+        }
+        super::disable_pics();
+
+        enable_local_apic();
+
+        let lapic_id = id();
+        let max_entry = (read_io_apic(IO_APIC_VERSION) >> 16) & 0xff;
+        for entry in 0..=max_entry {
+            set_redirection_masked(entry, true);
+        }
+        for line in (0..IRQ_LINES).filter(|&line| line != CASCADE_LINE) {
+            let gsi = ISA_IRQ_TO_GSI.lock()[line as usize];
+            let mode = ISA_IRQ_MODE.lock()[line as usize];
+            // 向量与 PIC 模式相同; fixed 模式, 物理目标, 极性和触发方式来自 MADT
+            let low = u32::from(PIC_1_OFFSET + line) | mode | REDIRECTION_MASKED;
+            write_io_apic(IO_APIC_REDIRECTION_TABLE + 2 * gsi + 1, lapic_id << 24);
+            write_io_apic(IO_APIC_REDIRECTION_TABLE + 2 * gsi, low);
+        }
+
+        ACTIVE.store(true, Ordering::SeqCst);
+
+        for line in (0..IRQ_LINES).filter(|&line| line != CASCADE_LINE && unmasked[line as usize]) {
+            set_irq_mask(line, false);
+        }
+    });
+
+    Ok(())
+}
+
+/// Enables the local APIC of an application processor.
+///
+/// `init` must have run on the bootstrap processor, which maps the registers
+/// (every local APIC is reached through the same address). Device interrupts
+/// are still only delivered to the bootstrap processor.
+pub fn init_ap() {
+    assert!(is_active(), "APIC not initialized on the bootstrap processor");
+    unsafe { enable_local_apic() };
+}
+
+/// Returns the ID of the current CPU's local APIC.
+pub fn id() -> u32 {
+    unsafe { read_local_apic(LAPIC_ID) >> 24 }
+}
+
+/// Sends an INIT IPI, which resets the CPU whose local APIC has the ID
+/// `apic_id` into the wait-for-SIPI state.
+///
+/// # Safety
+///
+/// The target CPU loses whatever it was running.
+pub unsafe fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI, which makes a CPU waiting for it start in real mode
+/// at physical address `page << 12`.
+///
+/// # Safety
+///
+/// The page must contain code that can start the CPU.
+pub unsafe fn send_startup(apic_id: u32, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    unsafe { write_local_apic(LAPIC_EOI, 0) };
+}
+
+/// Masks or unmasks the I/O APIC pin of ISA IRQ `line`.
+///
+/// Does nothing for the cascade line, which has no pin.
+pub fn set_irq_mask(line: u8, masked: bool) {
+    // ISA_IRQ_TO_GSI[2] 是 PIT 的 pin, 不能被 cascade 线改动
+    if line == CASCADE_LINE {
+        return;
+    }
+    let gsi = ISA_IRQ_TO_GSI.lock()[line as usize];
+    unsafe { set_redirection_masked(gsi, masked) };
+}
+
+/// Returns whether the I/O APIC pin of ISA IRQ `line` is masked.
+///
+/// The cascade line never raises interrupts and is always reported masked.
+pub fn is_irq_masked(line: u8) -> bool {
+    if line == CASCADE_LINE {
+        return true;
+    }
+    let gsi = ISA_IRQ_TO_GSI.lock()[line as usize];
+    unsafe { read_io_apic(IO_APIC_REDIRECTION_TABLE + 2 * gsi) & REDIRECTION_MASKED != 0 }
+}
+
+// local APIC 的伪中断不需要 EOI
+pub(super) extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = crate::percpu::enter_interrupt(stack_frame.code_segment);
+    let _stats = super::stats::enter(SPURIOUS_VECTOR);
+}
+
+// 每个 CPU 都要单独启用自己的 local APIC
+unsafe fn enable_local_apic() {
+    let mut apic_base = Msr::new(IA32_APIC_BASE_MSR);
+    apic_base.write(apic_base.read() | APIC_BASE_ENABLE);
+    write_local_apic(LAPIC_TPR, 0);
+    write_local_apic(LAPIC_LVT_TIMER, LVT_MASKED); // 时钟仍然使用 PIT
+    write_local_apic(LAPIC_LVT_LINT0, LVT_MASKED);
+    write_local_apic(LAPIC_LVT_LINT1, LVT_MASKED);
+    write_local_apic(LAPIC_LVT_ERROR, LVT_MASKED);
+    write_local_apic(LAPIC_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+// 先写目标再写命令, 写命令的低 32 位时发送
+unsafe fn send_ipi(apic_id: u32, command: u32) {
+    write_local_apic(LAPIC_ICR_HIGH, apic_id << 24);
+    write_local_apic(LAPIC_ICR_LOW, command);
+    while read_local_apic(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn read_local_apic(offset: usize) -> u32 {
+    ((LOCAL_APIC_VIRT_ADDR as usize + offset) as *const u32).read_volatile()
+}
+
+unsafe fn write_local_apic(offset: usize, value: u32) {
+    ((LOCAL_APIC_VIRT_ADDR as usize + offset) as *mut u32).write_volatile(value);
+}
+
+unsafe fn read_io_apic(register: u32) -> u32 {
+    ((IO_APIC_VIRT_ADDR as usize + IO_APIC_REGSEL) as *mut u32).write_volatile(register);
+    ((IO_APIC_VIRT_ADDR as usize + IO_APIC_WINDOW) as *const u32).read_volatile()
+}
+
+unsafe fn write_io_apic(register: u32, value: u32) {
+    ((IO_APIC_VIRT_ADDR as usize + IO_APIC_REGSEL) as *mut u32).write_volatile(register);
+    ((IO_APIC_VIRT_ADDR as usize + IO_APIC_WINDOW) as *mut u32).write_volatile(value);
+}
+
+unsafe fn set_redirection_masked(gsi: u32, masked: bool) {
+    let register = IO_APIC_REDIRECTION_TABLE + 2 * gsi;
+    let low = read_io_apic(register);
+    write_io_apic(
+        register,
+        if masked {
+            low | REDIRECTION_MASKED
+        } else {
+            low & !REDIRECTION_MASKED
+        },
+    );
+}
@@ -0,0 +1,225 @@
+//! Page fault 的分类, 以及让指定的内核函数在 page fault 之后继续执行的异常表 (exception table).
+//!
+//! 异常表中的每一项都是一对地址: 可能出错的指令和出错之后跳转到的 fixup 代码. page fault
+//! handler 发现出错的指令在表中时, 把中断栈帧中的 `instruction_pointer` 改成 fixup 的地址后返回,
+//! 而不是停机. 这些指令只能写在汇编里, 否则无法得到它们的确切地址.
+
+use core::arch::global_asm;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::VirtAddr;
+
+/// Faults below this address are reported as null pointer dereferences.
+pub const NULL_GUARD_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultKind {
+    /// Access to a not present page near address 0.
+    NullDereference,
+    /// Access to a not present page right next to the stack pointer, i.e. the
+    /// guard page below a stack was hit.
+    StackGuard,
+    /// Access to a page that is not mapped.
+    NotPresent,
+    /// Write to a present, read-only page.
+    WriteToReadOnly,
+    /// Instruction fetch from a present page marked `NO_EXECUTE`.
+    ExecuteNoExecute,
+    /// Any other protection violation, e.g. ring 3 accessing a kernel page.
+    ProtectionViolation,
+    /// A page table entry has a reserved bit set.
+    MalformedTable,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The accessed address, read from `Cr2`.
+    pub address: VirtAddr,
+    pub kind: PageFaultKind,
+    /// Whether the fault happened while running in ring 3.
+    pub user_mode: bool,
+}
+
+impl PageFault {
+    /// Classifies a page fault from the accessed address, the error code and
+    /// the stack pointer of the interrupted code.
+    pub fn new(address: VirtAddr, error_code: PageFaultErrorCode, stack_pointer: VirtAddr) -> Self {
+        let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        let fetch = error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+
+        let kind = if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            PageFaultKind::MalformedTable
+        } else if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            if fetch {
+                PageFaultKind::ExecuteNoExecute
+            } else if write {
+                PageFaultKind::WriteToReadOnly
+            } else {
+                PageFaultKind::ProtectionViolation
+            }
+        } else if address.as_u64() < NULL_GUARD_SIZE {
+            PageFaultKind::NullDereference
+        } else if is_near_stack_pointer(address, stack_pointer) {
+            PageFaultKind::StackGuard
+        } else {
+            PageFaultKind::NotPresent
+        };
+
+        PageFault {
+            address,
+            kind,
+            user_mode: error_code.contains(PageFaultErrorCode::USER_MODE),
+        }
+    }
+}
+
+/// 栈溢出时出错的地址就在栈指针附近 (push 在写入之前出错, 栈指针还没有改变)
+fn is_near_stack_pointer(address: VirtAddr, stack_pointer: VirtAddr) -> bool {
+    let distance = if address > stack_pointer {
+        address - stack_pointer
+    } else {
+        stack_pointer - address
+    };
+    distance < 4096
+}
+
+////////////////////////////////////////
+// 异常表
+////////////////////////////////////////
+
+struct ExceptionTableEntry {
+    instruction: unsafe extern "C" fn(),
+    fixup: unsafe extern "C" fn(),
+}
+
+// 这些符号都是下面汇编中的标号, 不是真正的函数, 只用来取地址
+extern "C" {
+    fn __probe_read_u8(addr: u64, value: *mut u8) -> u64;
+    fn __probe_read_u8_access();
+    fn __probe_read_u8_fixup();
+    fn __probe_write_u8(addr: u64, value: u8) -> u64;
+    fn __probe_write_u8_access();
+    fn __probe_write_u8_fixup();
+}
+
+static EXCEPTION_TABLE: &[ExceptionTableEntry] = &[
+    ExceptionTableEntry {
+        instruction: __probe_read_u8_access,
+        fixup: __probe_read_u8_fixup,
+    },
+    ExceptionTableEntry {
+        instruction: __probe_write_u8_access,
+        fixup: __probe_write_u8_fixup,
+    },
+];
+
+/// Returns the fixup address for a fault at `instruction_pointer`, if the
+/// faulting instruction is listed in the exception table.
+pub fn search_exception_table(instruction_pointer: VirtAddr) -> Option<VirtAddr> {
+    EXCEPTION_TABLE
+        .iter()
+        .find(|entry| entry.instruction as usize as u64 == instruction_pointer.as_u64())
+        .map(|entry| VirtAddr::new(entry.fixup as usize as u64))
+}
+
+// rdi: 要读取的地址, rsi: 保存结果的地址. 成功返回 1, 读取时发生 page fault 返回 0.
+global_asm!(
+    ".global __probe_read_u8",
+    ".global __probe_read_u8_access",
+    ".global __probe_read_u8_fixup",
+    "__probe_read_u8:",
+    "__probe_read_u8_access:",
+    "    mov al, byte ptr [rdi]",
+    "    mov byte ptr [rsi], al",
+    "    mov eax, 1",
+    "    ret",
+    "__probe_read_u8_fixup:",
+    "    xor eax, eax",
+    "    ret",
+);
+
+// rdi: 要写入的地址, sil: 写入的值. 成功返回 1, 写入时发生 page fault 返回 0.
+global_asm!(
+    ".global __probe_write_u8",
+    ".global __probe_write_u8_access",
+    ".global __probe_write_u8_fixup",
+    "__probe_write_u8:",
+    "__probe_write_u8_access:",
+    "    mov byte ptr [rdi], sil",
+    "    mov eax, 1",
+    "    ret",
+    "__probe_write_u8_fixup:",
+    "    xor eax, eax",
+    "    ret",
+);
+
+/// Reads the byte at `addr`, returning `None` instead of halting if the read
+/// causes a page fault.
+pub fn probe_read(addr: VirtAddr) -> Option<u8> {
+    let mut value = 0;
+    if unsafe { __probe_read_u8(addr.as_u64(), &mut value) } == 1 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Writes `value` to `addr`, returning `false` instead of halting if the
+/// write causes a page fault.
+///
+/// # Safety
+///
+/// The caller must make sure that overwriting the byte does not break any
+/// invariants, e.g. of data owned by Rust code.
+pub unsafe fn probe_write(addr: VirtAddr, value: u8) -> bool {
+    __probe_write_u8(addr.as_u64(), value) == 1
+}
+
+#[test_case]
+fn test_classify_page_fault() {
+    let rsp = VirtAddr::new(0x_4000_0000);
+    let classify =
+        |address: u64, error_code| PageFault::new(VirtAddr::new(address), error_code, rsp).kind;
+
+    assert_eq!(
+        classify(0x10, PageFaultErrorCode::empty()),
+        PageFaultKind::NullDereference
+    );
+    assert_eq!(
+        classify(0x_4000_0000 - 8, PageFaultErrorCode::CAUSED_BY_WRITE),
+        PageFaultKind::StackGuard
+    );
+    assert_eq!(
+        classify(0x_dead_0000, PageFaultErrorCode::empty()),
+        PageFaultKind::NotPresent
+    );
+    assert_eq!(
+        classify(
+            0x_dead_0000,
+            PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE
+        ),
+        PageFaultKind::WriteToReadOnly
+    );
+    assert_eq!(
+        classify(
+            0x_dead_0000,
+            PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH
+        ),
+        PageFaultKind::ExecuteNoExecute
+    );
+
+    let fault = PageFault::new(
+        VirtAddr::new(0x_dead_0000),
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::USER_MODE,
+        rsp,
+    );
+    assert_eq!(fault.kind, PageFaultKind::ProtectionViolation);
+    assert!(fault.user_mode);
+}
+
+#[test_case]
+fn test_probe_read() {
+    let value = 42u8;
+    assert_eq!(probe_read(VirtAddr::from_ptr(&value)), Some(42));
+    assert_eq!(probe_read(VirtAddr::new(0)), None);
+    assert_eq!(probe_read(VirtAddr::new(0x_7777_0000_0000)), None);
+}
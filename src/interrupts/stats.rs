@@ -0,0 +1,167 @@
+//! 每个中断向量的统计: 发生的次数和 handler 运行的时间 (用 TSC 计时), 类似 `/proc/interrupts`.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const VECTORS: usize = 256;
+
+// AtomicU64 不是 Copy, 只能通过常量来初始化数组
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+static CYCLES: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+
+/// Counts one interrupt on `vector` and measures the handler until the
+/// returned guard is dropped.
+///
+/// Handlers that never return (e.g. because they panic) are counted, but
+/// their time is not.
+pub fn enter(vector: u8) -> HandlerTimer {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+    HandlerTimer {
+        vector,
+        start: read_tsc(),
+    }
+}
+
+/// Measures a running handler, see `enter`.
+pub struct HandlerTimer {
+    vector: u8,
+    start: u64,
+}
+
+impl Drop for HandlerTimer {
+    fn drop(&mut self) {
+        let elapsed = read_tsc().wrapping_sub(self.start);
+        CYCLES[self.vector as usize].fetch_add(elapsed, Ordering::Relaxed);
+    }
+}
+
+fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns how often `vector` was raised.
+pub fn count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Returns the TSC cycles spent in the handlers of `vector`.
+pub fn cycles(vector: u8) -> u64 {
+    CYCLES[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Returns a short name for `vector`.
+pub fn vector_name(vector: u8) -> &'static str {
+    use super::{apic, PIC_1_OFFSET};
+
+    const EXCEPTIONS: [&str; 32] = [
+        "Divide error",
+        "Debug",
+        "Non-maskable interrupt",
+        "Breakpoint",
+        "Overflow",
+        "Bound range exceeded",
+        "Invalid opcode",
+        "Device not available",
+        "Double fault",
+        "Coprocessor segment overrun",
+        "Invalid TSS",
+        "Segment not present",
+        "Stack segment fault",
+        "General protection fault",
+        "Page fault",
+        "Reserved",
+        "x87 floating point",
+        "Alignment check",
+        "Machine check",
+        "SIMD floating point",
+        "Virtualization",
+        "Reserved",
+        "Reserved",
+        "Reserved",
+        "Reserved",
+        "Reserved",
+        "Reserved",
+        "Reserved",
+        "Reserved",
+        "VMM communication",
+        "Security exception",
+        "Reserved",
+    ];
+    const IRQS: [&str; 16] = [
+        "IRQ 0 (timer)",
+        "IRQ 1 (keyboard)",
+        "IRQ 2 (cascade)",
+        "IRQ 3",
+        "IRQ 4",
+        "IRQ 5",
+        "IRQ 6",
+        "IRQ 7",
+        "IRQ 8",
+        "IRQ 9",
+        "IRQ 10",
+        "IRQ 11",
+        "IRQ 12",
+        "IRQ 13",
+        "IRQ 14",
+        "IRQ 15",
+    ];
+
+    match vector {
+        0..=31 => EXCEPTIONS[vector as usize],
+        _ if vector == apic::SPURIOUS_VECTOR => "APIC spurious",
+        _ if (PIC_1_OFFSET..PIC_1_OFFSET + 16).contains(&vector) => {
+            IRQS[(vector - PIC_1_OFFSET) as usize]
+        }
+        _ => "",
+    }
+}
+
+/// Formats the statistics of every vector that was raised at least once,
+/// one line per vector.
+///
+/// ```ignore
+/// println!("{}", Report);
+/// ```
+pub struct Report;
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>6}  {:<28}{:>10}{:>16}{:>12}",
+            "vector", "name", "count", "cycles", "avg"
+        )?;
+        for vector in 0..=255u8 {
+            let count = count(vector);
+            if count == 0 {
+                continue;
+            }
+            let cycles = cycles(vector);
+            writeln!(
+                f,
+                "{:>6}  {:<28}{:>10}{:>16}{:>12}",
+                vector,
+                vector_name(vector),
+                count,
+                cycles,
+                cycles / count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the interrupt statistics to the screen. The keyboard task calls
+/// this when F1 is pressed.
+pub fn print() {
+    crate::println!("{}", Report);
+}
+
+#[test_case]
+fn test_breakpoint_counted() {
+    let before = count(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(count(3), before + 1);
+    assert!(cycles(3) > 0);
+}
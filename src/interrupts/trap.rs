@@ -0,0 +1,122 @@
+//! debug (#DB) 和 breakpoint (#BP) 异常的汇编入口.
+//!
+//! `extern "x86-interrupt"` 的 handler 只能看到中断栈帧, 而调试器需要读写所有的通用寄存器,
+//! 所以这两个异常先进入这里的汇编代码, 把通用寄存器保存成一个 `TrapFrame`, 返回前再恢复.
+
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+/// The registers of the interrupted code, as saved by the entry stubs.
+///
+/// Changes made by the handler are restored when it returns.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // 以下是 CPU 压入的中断栈帧
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+extern "C" {
+    fn __debug_entry();
+    fn __breakpoint_entry();
+}
+
+/// Address of the entry stub for the debug exception.
+pub fn debug_entry() -> VirtAddr {
+    VirtAddr::new(__debug_entry as usize as u64)
+}
+
+/// Address of the entry stub for the breakpoint exception.
+pub fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::new(__breakpoint_entry as usize as u64)
+}
+
+#[no_mangle]
+extern "C" fn __trap_dispatch(frame: &mut TrapFrame) {
+    match frame.vector {
+        1 => super::debug_handler(frame),
+        3 => super::breakpoint_handler(frame),
+        vector => panic!("unexpected trap vector {}", vector),
+    }
+}
+
+// 进入时 CPU 已经压入了 5 项的中断栈帧 (这两个异常没有错误码). 压入向量号和 15 个通用寄存器之后,
+// 栈指针就是 TrapFrame 的地址. 中断栈帧之前栈指针是 16 字节对齐的, 这里一共压入了 21 项,
+// 所以 call 之前还要再减 8. 从 ring 3 进入时 (栈帧中 cs 的 RPL 是 3) 用 swapgs 换成内核的 GS 基址,
+// iretq 之前再换回来, 见 percpu 模块.
+global_asm!(
+    ".global __debug_entry",
+    ".global __breakpoint_entry",
+    "__debug_entry:",
+    "    push 1",
+    "    jmp __trap_common",
+    "__breakpoint_entry:",
+    "    push 3",
+    "    jmp __trap_common",
+    "__trap_common:",
+    "    test byte ptr [rsp + 16], 3",
+    "    jz 1f",
+    "    swapgs",
+    "1:",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov rdi, rsp",
+    "    sub rsp, 8",
+    "    cld",
+    "    call __trap_dispatch",
+    "    add rsp, 8",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    add rsp, 8",
+    "    test byte ptr [rsp + 8], 3",
+    "    jz 2f",
+    "    swapgs",
+    "2:",
+    "    iretq",
+);
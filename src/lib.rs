@@ -12,12 +12,21 @@
 use core::panic::PanicInfo;
 extern crate alloc; // new
 
+pub mod acpi;
 pub mod allocator; // new
+pub mod backtrace;
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod percpu;
+pub mod power;
 pub mod serial; // 其中标记有 #[test_case] 的 module 都会被测试
+pub mod smp;
+pub mod syscall;
 pub mod task;
+pub mod time;
+pub mod usermode;
 pub mod vga_buffer; // 其中标记有 #[test_case] 的 module 都会被测试
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -46,8 +55,10 @@ where
 {
     fn run(&self) -> () {
         serial_print!("{}... \t", core::any::type_name::<T>());
+        let start = time::tsc::monotonic_nanos();
         self();
-        serial_println!("[ok]");
+        let elapsed = time::tsc::monotonic_nanos() - start;
+        serial_println!("[ok] ({}.{:03}ms)", elapsed / 1_000_000, elapsed / 1_000 % 1_000);
     }
 }
 
@@ -70,11 +81,95 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
+/// Panic handler for tests that are expected to panic with a message
+/// containing `expected`, e.g. the name of the exception they trigger.
+pub fn test_expected_panic_handler(info: &PanicInfo, expected: &str) -> ! {
+    use core::fmt::Write;
+
+    let mut message = PanicMessage {
+        buf: [0; 1024],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+
+    if message.as_str().contains(expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Expected panic containing {:?}, got: {}\n", expected, info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
+/// Entry point of a test that expects `trigger` to raise an exception whose
+/// handler panics. Fails the test if `trigger` returns.
+pub fn test_exception(name: &str, trigger: fn()) -> ! {
+    serial_print!("{}...\t", name);
+
+    init();
+    trigger();
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+/// Defines `_start` and the panic handler of an exception test, see
+/// `test_exception` and `test_expected_panic_handler`.
+///
+/// 这类测试的 handler 会 panic, 所以不使用测试框架 (在 Cargo.toml 的 [[test]] 中关闭)
+#[macro_export]
+macro_rules! exception_test {
+    ($name:expr, $expected:expr, $trigger:expr) => {
+        #[panic_handler]
+        fn panic(info: &core::panic::PanicInfo) -> ! {
+            $crate::test_expected_panic_handler(info, $expected)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn _start() -> ! {
+            $crate::test_exception($name, $trigger)
+        }
+    };
+}
+
+/// 没有堆的情况下用来保存 panic 信息的缓冲区, 超出的部分被丢弃
+struct PanicMessage {
+    buf: [u8; 1024],
+    len: usize,
+}
+
+impl PanicMessage {
+    fn as_str(&self) -> &str {
+        match core::str::from_utf8(&self.buf[..self.len]) {
+            Ok(s) => s,
+            // 截断可能发生在一个多字节字符的中间
+            Err(e) => core::str::from_utf8(&self.buf[..e.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl core::fmt::Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
 pub fn init() {
     // init os
     interrupts::init_idt();
     gdt::init();
-    unsafe { interrupts::PICS.lock().initialize() };
+    percpu::init();
+    syscall::init();
+    interrupts::init_pics();
+    time::init();
+    time::tsc::init();
+    task::timer::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -93,8 +188,10 @@ entry_point!(test_kernel_main);
 #[cfg(test)]
 // #[no_mangle] // 不要重命名函数的名称
 // pub extern "C" fn _start() -> ! { // 所有 library crate 中的单元测试入口
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    // 失败的原因由 backtrace 模块的 test_symbolize 报告
+    let _ = backtrace::init(boot_info);
 
     serial_println!("Start unittests for lib.");
 
@@ -10,7 +10,7 @@
 use blog_os::serial_println; // 引用宏不用包含 module 名称
 use blog_os::{
     println,
-    task::{executor::Executor, keyboard::keyboard_task, simple_executor::SimpleExecutor, Task},
+    task::{executor::Executor, keyboard::keyboard_task, simple_executor::SimpleExecutor, Priority, Task},
 }; // 引用宏不用包含 module 名称
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
@@ -21,6 +21,7 @@ extern crate alloc;
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     println!("{}", _info);
+    blog_os::backtrace::print();
     blog_os::hlt_loop();
 }
 
@@ -37,6 +38,7 @@ entry_point!(kernel_main); // 定义入口
 // pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! { // cargo run 和 cargo test 都会进入这里
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use blog_os::allocator; // new import
+    use blog_os::interrupts;
     use blog_os::memory::{self, BootInfoFrameAllocator};
     use x86_64::VirtAddr;
 
@@ -44,12 +46,68 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // init os
     blog_os::init();
+    if let Err(err) = blog_os::backtrace::init(boot_info) {
+        println!("backtraces without symbols: {}", err);
+    }
+
+    // 用 `--features gdb` 编译时, 在这里等待 GDB 连接
+    if cfg!(feature = "gdb") {
+        blog_os::gdb::init();
+        println!("waiting for GDB on COM2");
+        blog_os::gdb::breakpoint();
+    }
 
     ////////////////////////////////////
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset); // 这个 offset 是物理地址在虚拟地址中的偏移量, 它是一个虚拟地址
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+
+    // 打印 ACPI 表, 并把 MADT 中的 I/O APIC 地址和 ISA 中断重定向告诉 APIC
+    match blog_os::acpi::init() {
+        Ok(tables) => {
+            println!("{}", tables);
+            // 有 HPET 的话用它重新校准 TSC, 它比 PIT 精确
+            if let Some(hpet) = &tables.hpet {
+                match blog_os::time::hpet::init(&mut mapper, hpet) {
+                    Ok(()) => blog_os::time::tsc::recalibrate(),
+                    Err(err) => println!("HPET: {:?}", err),
+                }
+            }
+            if let Some(madt) = &tables.madt {
+                if let Some(io_apic) = madt.io_apics.iter().find(|io_apic| io_apic.gsi_base == 0) {
+                    interrupts::apic::set_io_apic_address(io_apic.address);
+                }
+                for entry in &madt.interrupt_overrides {
+                    interrupts::apic::set_isa_irq_override(
+                        entry.irq,
+                        entry.gsi,
+                        entry.active_low(),
+                        entry.level_triggered(),
+                    );
+                }
+            }
+        }
+        Err(err) => println!("ACPI: {:?}", err),
+    }
+
+    // CPU 支持 APIC 并且 MADT 中有 I/O APIC 时用 APIC 代替 8259 PIC, 否则 (或者 APIC 初始化失败时)
+    // 继续使用 PIC. 在 QEMU 中可以用 `-cpu qemu64,-apic` 走 PIC 的路径
+    let has_io_apic = blog_os::acpi::tables()
+        .and_then(|tables| tables.madt.as_ref())
+        .map_or(false, |madt| madt.io_apics.iter().any(|io_apic| io_apic.gsi_base == 0));
+    if !interrupts::apic::is_supported() || !has_io_apic {
+        println!("no APIC, using the 8259 PIC");
+    } else if let Err(err) = interrupts::apic::init(&mut mapper) {
+        println!("APIC: {:?}, using the 8259 PIC", err);
+    } else {
+        // 启动其它 CPU, 每个 CPU 上线时打印一行
+        match blog_os::smp::init(&mut mapper) {
+            Ok(count) => println!("{} CPUs online", count),
+            Err(err) => println!("SMP: {:?}", err),
+        }
+    }
 
     use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 
@@ -65,7 +123,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut executor = SimpleExecutor::new();
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard_task()));
+    // 键盘任务处理中断处理函数放进队列的扫描码
+    executor.spawn(Task::new(keyboard_task()).with_priority(Priority::Interrupt));
     executor.run();
     ////////////////////////////////////
 
@@ -2,11 +2,23 @@ use x86_64::{
     structures::paging::PageTable,
     structures::paging::page_table::PageTableEntry,
     VirtAddr,
-    registers::control::Cr3,
+    registers::control::{Cr0, Cr0Flags, Cr3},
     addr::PhysAddr,
     structures::paging::frame::PhysFrame,
     structures::paging::OffsetPageTable
 };
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+
+pub mod cow;
+
+/// 物理内存在虚拟地址空间中的偏移量, 由 `init` 记录下来, 供需要访问物理内存的模块使用.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// 内核自己的第四级页表, 即 bootloader 交给我们的那一张.
+static KERNEL_LEVEL_4_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -16,6 +28,10 @@ use x86_64::{
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 // OffsetPageTable 是一个 Mapper, 将物理
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.init_once(|| physical_memory_offset);
+    KERNEL_LEVEL_4_FRAME.init_once(|| Cr3::read().0);
+    // 让内核写只读页时也触发 page fault, copy-on-write 依赖于此
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -41,6 +57,17 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     &mut *page_table_ptr // unsafe
 }
 
+/// Returns the virtual address through which the given physical address can
+/// be accessed.
+///
+/// Panics if `init` has not been called yet.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("memory::init has not been called");
+    *offset + addr.as_u64()
+}
+
 
 ////////////////////////////////////////
 // BootInfoFrameAllocator
@@ -49,22 +76,44 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
 use bootloader::bootinfo::MemoryMap;
 use bootloader::bootinfo::MemoryRegionType;
 use x86_64::{
-    structures::paging::{Page, Mapper, Size4KiB, FrameAllocator}
+    structures::paging::{Page, Mapper, Size4KiB, FrameAllocator, FrameDeallocator}
 };
 
+// 实模式只能访问 1MB 以下的内存
+const LOW_MEMORY_LIMIT: u64 = 0x10_0000;
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    low_frame: Option<PhysFrame>, // init 时留出的 1MB 以下的 frame, 见 take_low_frame
+    free_list: Option<PhysFrame>, // 被释放的 frame 组成的链表, 下一个节点的地址写在 frame 自身的开头
+    ref_counts: BTreeMap<PhysFrame, usize>, // 被多个映射共享的 frame 的引用计数, 不在其中的 frame 只有一个所有者
 }
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // 优先复用已释放的 frame
+        if let Some(frame) = self.free_list {
+            let node: *const Option<PhysFrame> = phys_to_virt(frame.start_address()).as_ptr();
+            self.free_list = unsafe { node.read() };
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        // 把 frame 放到链表头, frame 的内存已经不再使用, 所以可以用来保存链表节点
+        let node: *mut Option<PhysFrame> = phys_to_virt(frame.start_address()).as_mut_ptr();
+        node.write(self.free_list);
+        self.free_list = Some(frame);
+    }
+}
+
 impl BootInfoFrameAllocator {
     /// Create a FrameAllocator from the passed memory map.
     ///
@@ -72,9 +121,64 @@ impl BootInfoFrameAllocator {
     /// memory map is valid. The main requirement is that all frames that are marked
     /// as `USABLE` in it are really unused.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let mut allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            low_frame: None,
+            free_list: None,
+            ref_counts: BTreeMap::new(),
+        };
+        // memory map 按地址排序, 第一个可用的 frame 在 1MB 以下的话就把它留出来,
+        // 这样无论之前分配了多少 frame, 实模式的代码都能拿到它
+        if let Some(frame) = allocator.usable_frames().next() {
+            if frame.start_address().as_u64() < LOW_MEMORY_LIMIT {
+                allocator.low_frame = Some(frame);
+                allocator.next = 1;
+            }
+        }
+        allocator
+    }
+
+    /// Takes the frame below 1MiB that `init` reserved, e.g. for code that
+    /// runs in real mode. Returns `None` if the memory map has no usable
+    /// frame below 1MiB or the frame was already taken.
+    pub fn take_low_frame(&mut self) -> Option<PhysFrame> {
+        self.low_frame.take()
+    }
+
+    /// Returns how many mappings refer to `frame`.
+    ///
+    /// Frames that were never shared count as having a single owner.
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        self.ref_counts.get(&frame).copied().unwrap_or(1)
+    }
+
+    /// Records one more mapping of `frame`.
+    ///
+    /// Needs the heap, because the counts of shared frames live in a `BTreeMap`.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        *self.ref_counts.entry(frame).or_insert(1) += 1;
+    }
+
+    /// Drops one mapping of `frame` and frees it once no mapping is left.
+    ///
+    /// Returns whether the frame was freed.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// dropped mapping is no longer used.
+    pub unsafe fn release_frame(&mut self, frame: PhysFrame) -> bool {
+        match self.ref_counts.get_mut(&frame) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    self.ref_counts.remove(&frame);
+                }
+                false
+            }
+            None => {
+                self.deallocate_frame(frame);
+                true
+            }
         }
     }
     
@@ -93,3 +197,351 @@ impl BootInfoFrameAllocator {
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 }
+
+////////////////////////////////////////
+// 全局 frame allocator
+////////////////////////////////////////
+
+/// The frame allocator shared by the whole kernel, set by `init_frame_allocator`.
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// Hands the frame allocator over to `FRAME_ALLOCATOR`, so that code without
+/// access to `kernel_main`'s locals (e.g. `AddressSpace::drop`) can use it.
+pub fn init_frame_allocator(frame_allocator: BootInfoFrameAllocator) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    });
+}
+
+/// Runs `f` with the global frame allocator locked.
+///
+/// Interrupts are disabled while the lock is held, so an interrupt handler
+/// that needs frames cannot deadlock against the code it interrupted.
+/// Panics if `init_frame_allocator` has not been called yet.
+pub fn with_frame_allocator<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BootInfoFrameAllocator) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        f(allocator.as_mut().expect("frame allocator not initialized"))
+    })
+}
+
+/// Like `with_frame_allocator`, but returns `None` instead of waiting if the
+/// allocator is locked.
+///
+/// For fault handlers: a fault raised while the faulting code holds the lock
+/// would wait for itself forever.
+pub fn try_with_frame_allocator<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut BootInfoFrameAllocator) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR.try_lock()?;
+        Some(f(allocator.as_mut().expect("frame allocator not initialized")))
+    })
+}
+
+/// Maps the page of memory mapped device registers at `phys` to the page at
+/// `virt`, uncached. Mapping the same frame twice is not an error.
+pub fn map_mmio(
+    mapper: &mut impl Mapper<Size4KiB>,
+    phys: u64,
+    virt: u64,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::containing_address(VirtAddr::new(virt));
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+    // 寄存器不能被缓存
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+
+    match with_frame_allocator(|allocator| unsafe { mapper.map_to(page, frame, flags, allocator) }) {
+        Ok(flush) => flush.flush(),
+        // 已经映射过了 (例如第二次调用 init)
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+        Err(error) => return Err(error),
+    }
+    Ok(())
+}
+
+////////////////////////////////////////
+// AddressSpace
+////////////////////////////////////////
+
+use x86_64::registers::control::Cr3Flags;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{PageSize, PageTableFlags, PageTableIndex};
+
+/// A page table hierarchy of its own, used to isolate a process.
+///
+/// The level 4 table starts out as a copy of the kernel's level 4 table, so
+/// the kernel (code, stack, heap and the physical memory mapping) stays
+/// mapped after switching to it. User pages are always mapped
+/// `USER_ACCESSIBLE`; that flag is also how the level 4 entries owned by the
+/// address space are told apart from the shared kernel entries.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates a new address space that only contains the kernel mappings.
+    ///
+    /// Returns `None` if there is no frame left for the level 4 table.
+    pub fn new() -> Option<Self> {
+        let level_4_frame = with_frame_allocator(|allocator| allocator.allocate_frame())?;
+        let kernel_frame = *KERNEL_LEVEL_4_FRAME
+            .get()
+            .expect("memory::init has not been called");
+
+        let kernel_table = unsafe { frame_to_table(kernel_frame) };
+        let table = unsafe { frame_to_table(level_4_frame) };
+        table.zero();
+        for (entry, kernel_entry) in table.iter_mut().zip(kernel_table.iter()) {
+            if !kernel_entry.is_unused() {
+                // 共享内核的下级页表, 但不能让它们被当作用户页表释放
+                entry.set_addr(
+                    kernel_entry.addr(),
+                    kernel_entry.flags() - PageTableFlags::USER_ACCESSIBLE,
+                );
+            }
+        }
+
+        Some(AddressSpace { level_4_frame })
+    }
+
+    /// Returns the frame of the level 4 table, i.e. the value loaded into `Cr3`.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns whether this address space is the one currently loaded in `Cr3`.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Returns a mapper for the page tables of this address space.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let physical_memory_offset = *PHYSICAL_MEMORY_OFFSET
+            .get()
+            .expect("memory::init has not been called");
+        unsafe { OffsetPageTable::new(frame_to_table(self.level_4_frame), physical_memory_offset) }
+    }
+
+    /// Allocates a zeroed frame and maps it at `page` as a user page.
+    ///
+    /// `PRESENT` and `USER_ACCESSIBLE` are added to `flags`. Panics if `page`
+    /// lies in a level 4 slot shared with the kernel.
+    pub fn map_user_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        self.assert_user_page(page);
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+        with_frame_allocator(|allocator| {
+            let frame = allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                let frame_ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+                frame_ptr.write_bytes(0, Size4KiB::SIZE as usize);
+
+                let flush = mapper.map_to(page, frame, flags, allocator)?;
+                // 不在使用中的页表不会被 TLB 缓存
+                if active {
+                    flush.flush();
+                } else {
+                    flush.ignore();
+                }
+            }
+            Ok(frame)
+        })
+    }
+
+    /// Unmaps the user page `page` and frees the frame it was mapped to.
+    ///
+    /// Panics if `page` lies in a level 4 slot shared with the kernel.
+    pub fn unmap_user_page(&mut self, page: Page) -> Result<(), UnmapError> {
+        self.assert_user_page(page);
+
+        let active = self.is_active();
+        let (frame, flush) = self.mapper().unmap(page)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        with_frame_allocator(|allocator| unsafe { allocator.release_frame(frame) });
+        Ok(())
+    }
+
+    /// Creates a copy of this address space in which all user pages are
+    /// shared copy-on-write with this one (see `cow`).
+    pub fn fork(&mut self) -> Result<AddressSpace, cow::CowError> {
+        let mut child = AddressSpace::new()
+            .ok_or(cow::CowError::Map(MapToError::FrameAllocationFailed))?;
+
+        let pages = self.user_pages();
+        let mut parent_mapper = self.mapper();
+        let mut child_mapper = child.mapper();
+        for page in pages {
+            cow::share_page(&mut parent_mapper, page, &mut child_mapper, page)?;
+        }
+
+        Ok(child)
+    }
+
+    /// 列出该地址空间中所有已映射的用户页
+    fn user_pages(&self) -> Vec<Page> {
+        let mut pages = Vec::new();
+        let level_4_table = unsafe { frame_to_table(self.level_4_frame) };
+        for (p4_index, level_3_frame) in present_frames(level_4_table) {
+            if !level_4_table[p4_index].flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+                continue;
+            }
+            for (p3_index, level_2_frame) in present_frames(unsafe { frame_to_table(level_3_frame) }) {
+                for (p2_index, level_1_frame) in present_frames(unsafe { frame_to_table(level_2_frame) }) {
+                    for (p1_index, _) in present_frames(unsafe { frame_to_table(level_1_frame) }) {
+                        pages.push(Page::from_page_table_indices(p4_index, p3_index, p2_index, p1_index));
+                    }
+                }
+            }
+        }
+        pages
+    }
+
+    /// Loads this address space into `Cr3`.
+    ///
+    /// This function is unsafe because all references into user pages of the
+    /// previously active address space become dangling.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    fn assert_user_page(&self, page: Page) {
+        let entry = &unsafe { frame_to_table(self.level_4_frame) }[page.p4_index()];
+        assert!(
+            entry.is_unused() || entry.flags().contains(PageTableFlags::USER_ACCESSIBLE),
+            "{:?} lies in a level 4 slot shared with the kernel",
+            page
+        );
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe { activate_kernel_address_space() };
+        }
+
+        let level_4_table = unsafe { frame_to_table(self.level_4_frame) };
+        with_frame_allocator(|allocator| unsafe {
+            // 只释放属于该地址空间的页表, 共享的内核页表没有 USER_ACCESSIBLE 标记
+            for entry in level_4_table
+                .iter()
+                .filter(|entry| entry.flags().contains(PageTableFlags::USER_ACCESSIBLE))
+            {
+                free_table(entry, 3, allocator);
+            }
+            allocator.deallocate_frame(self.level_4_frame);
+        });
+    }
+}
+
+/// Loads the kernel's own level 4 table into `Cr3`.
+///
+/// This function is unsafe for the same reason as `AddressSpace::activate`.
+pub unsafe fn activate_kernel_address_space() {
+    let kernel_frame = *KERNEL_LEVEL_4_FRAME
+        .get()
+        .expect("memory::init has not been called");
+    Cr3::write(kernel_frame, Cr3Flags::empty());
+}
+
+/// Returns whether the `len` bytes at `start` are mapped as user pages in the
+/// active address space, and also writable if `write` is set.
+///
+/// Used to check pointers passed in by user programs before the kernel
+/// touches them.
+pub fn is_user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let end = match start
+        .as_u64()
+        .checked_add(len - 1)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+    {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let level_4_frame = Cr3::read().0;
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(end),
+    );
+    for page in pages {
+        // 每一级页表的 entry 都要允许用户访问
+        let mut table = unsafe { frame_to_table(level_4_frame) };
+        let indexes = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+        for (level, &index) in indexes.iter().enumerate() {
+            let entry = &table[index];
+            if !entry.flags().contains(required) {
+                return false;
+            }
+            if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                break;
+            }
+            table = unsafe { frame_to_table(PhysFrame::containing_address(entry.addr())) };
+        }
+    }
+    true
+}
+
+/// 通过物理内存映射访问位于 `frame` 的页表
+unsafe fn frame_to_table(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// 释放 `entry` 指向的 frame, `level` 是该 frame 中页表的级别, 0 表示普通的数据页.
+/// 页表会先递归释放它下面的所有 frame, 数据页可能是共享的, 所以只减少其引用计数.
+unsafe fn free_table(entry: &PageTableEntry, level: u8, allocator: &mut BootInfoFrameAllocator) {
+    // 不存在的 entry 或 huge page (我们从不创建) 直接跳过
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+
+    if level == 0 {
+        allocator.release_frame(frame);
+        return;
+    }
+
+    for child in frame_to_table(frame).iter() {
+        free_table(child, level - 1, allocator);
+    }
+    allocator.deallocate_frame(frame);
+}
+
+/// 返回 `table` 中所有存在的 entry 的索引和它指向的 frame
+fn present_frames(table: &PageTable) -> impl Iterator<Item = (PageTableIndex, PhysFrame)> + '_ {
+    table.iter().enumerate().filter_map(|(index, entry)| {
+        entry
+            .frame()
+            .ok()
+            .map(|frame| (PageTableIndex::new(index as u16), frame))
+    })
+}
@@ -0,0 +1,175 @@
+//! Copy-on-write 共享页.
+//!
+//! 共享的页在所有映射中都是只读的, 并带有 `COPY_ON_WRITE` 标记. 对它的第一次写入会触发
+//! page fault, `handle_write_fault` 为写入者复制一份 frame, 之后两个映射各自独立.
+
+use super::{
+    active_level_4_table, phys_to_virt, try_with_frame_allocator, with_frame_allocator,
+    PHYSICAL_MEMORY_OFFSET,
+};
+use x86_64::structures::paging::mapper::{MapToError, MappedFrame, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::VirtAddr;
+
+/// Marks a read-only page whose frame is shared copy-on-write.
+///
+/// Only pages that were writable before sharing get this flag, so resolving a
+/// fault means making the page writable again.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug)]
+pub enum CowError {
+    /// The source page is not mapped to a 4KiB frame.
+    NotMapped,
+    /// Mapping the destination page failed.
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for CowError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        CowError::Map(error)
+    }
+}
+
+/// Maps `dst_page` in `dst` to the frame behind `src_page` in `src`.
+///
+/// If the source page is writable, both mappings become read-only and are
+/// marked `COPY_ON_WRITE`.
+pub fn share_page(
+    src: &mut OffsetPageTable,
+    src_page: Page,
+    dst: &mut OffsetPageTable,
+    dst_page: Page,
+) -> Result<(), CowError> {
+    let (frame, flags) = mark_shared(src, src_page)?;
+    map_shared(dst, dst_page, frame, flags)
+}
+
+/// Like `share_page`, but for two pages of the same page table.
+pub fn duplicate_page(
+    mapper: &mut OffsetPageTable,
+    src_page: Page,
+    dst_page: Page,
+) -> Result<(), CowError> {
+    let (frame, flags) = mark_shared(mapper, src_page)?;
+    map_shared(mapper, dst_page, frame, flags)
+}
+
+/// 把 `page` 标记为共享, 返回它的 frame 和共享后的 flags
+fn mark_shared(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+) -> Result<(PhysFrame, PageTableFlags), CowError> {
+    let (frame, mut flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        _ => return Err(CowError::NotMapped),
+    };
+
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags.remove(PageTableFlags::WRITABLE);
+        flags.insert(COPY_ON_WRITE);
+        unsafe {
+            mapper
+                .update_flags(page, flags)
+                .map_err(|_| CowError::NotMapped)?
+                .flush();
+        }
+    }
+
+    Ok((frame, flags))
+}
+
+/// 把 `page` 映射到共享的 `frame` 上, 并增加 frame 的引用计数
+fn map_shared(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), CowError> {
+    with_frame_allocator(|allocator| {
+        // 上级页表的 flags 与页本身相同, 但共享的页是只读的, 所以上级页表要保持可写,
+        // 否则以后在同一区域映射的可写页也会变成只读
+        let parent_flags = (flags & (PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE))
+            | PageTableFlags::WRITABLE;
+        unsafe {
+            mapper
+                .map_to_with_table_flags(page, frame, flags, parent_flags, allocator)?
+                .flush();
+        }
+        allocator.share_frame(frame);
+        Ok(())
+    })
+}
+
+/// Resolves a write fault at `addr` in the active page table.
+///
+/// Returns `false` if the page is not a copy-on-write page, i.e. the fault is
+/// a real protection violation. Called by the page fault handler.
+///
+/// Panics if the frame allocator is locked: the fault may have interrupted
+/// the code holding the lock, so waiting for it could deadlock.
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    let physical_memory_offset = match PHYSICAL_MEMORY_OFFSET.get() {
+        Some(offset) => *offset,
+        None => return false,
+    };
+    let mut mapper =
+        unsafe { OffsetPageTable::new(active_level_4_table(physical_memory_offset), physical_memory_offset) };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return false,
+    };
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    let resolved = try_with_frame_allocator(|allocator| {
+        if allocator.ref_count(frame) == 1 {
+            // 其他映射都已经复制走了, 直接把页变回可写
+            unsafe {
+                mapper
+                    .update_flags(page, flags)
+                    .expect("copy-on-write page vanished")
+                    .flush();
+            }
+            return true;
+        }
+
+        let new_frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe {
+            let src: *const u8 = phys_to_virt(frame.start_address()).as_ptr();
+            let dst: *mut u8 = phys_to_virt(new_frame.start_address()).as_mut_ptr();
+            core::ptr::copy_nonoverlapping(src, dst, Size4KiB::SIZE as usize);
+
+            let (_, flush) = mapper.unmap(page).expect("copy-on-write page vanished");
+            flush.ignore();
+            mapper
+                .map_to(page, new_frame, flags, allocator)
+                .expect("remapping copy-on-write page failed")
+                .flush();
+            allocator.release_frame(frame);
+        }
+        true
+    });
+    match resolved {
+        Some(resolved) => resolved,
+        None => panic!(
+            "copy-on-write fault at {:?} while the frame allocator is locked",
+            addr
+        ),
+    }
+}
@@ -0,0 +1,139 @@
+//! 每个 CPU 私有的数据, 通过 GS 段的基址找到.
+//!
+//! GS 基址指向当前 CPU 的 `PerCpu`, 它的第一个字段是自己的地址, 所以 `mov reg, gs:[0]`
+//! 一条指令就能得到引用.
+//!
+//! 用户程序可以加载 GS 段, 这会把 GS 基址清零, 所以运行用户程序时 `PerCpu` 的地址保存在
+//! KernelGsBase 中, 每次在 ring 3 和内核之间切换都用 `swapgs` 交换两者: `syscall` 入口和
+//! `sysret` 之前, `usermode` 的 `iretq` 之前, trap 模块的汇编入口, 以及其它中断 handler 开头的
+//! `enter_interrupt`. 在内核中 GS 基址总是指向 `PerCpu`, KernelGsBase 保存用户程序的 GS 基址.
+//!
+//! 使用 IST 的 handler (NMI, double fault, machine check) 可能打断 `swapgs` 和返回 ring 3
+//! 之间的指令, 这时无法判断 GS 基址属于谁, 所以它们不交换, 也不能使用 per-CPU 数据.
+
+use crate::gdt::{self, TssCell};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::GS;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+// BSP 安装了 per-CPU 数据之后, 其它 CPU 在运行任何内核代码之前都会先安装自己的
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref BOOT_CPU: PerCpu = PerCpu::new(0, cpuid_apic_id(), gdt::boot_tss());
+}
+
+/// Data private to one CPU.
+#[repr(C)]
+pub struct PerCpu {
+    // 必须是第一个字段, 见模块文档
+    self_ptr: AtomicPtr<PerCpu>,
+    // syscall 入口切换到的内核栈和保存的用户栈指针, 汇编代码通过 gs:[8] 和 gs:[16] 访问
+    pub(crate) syscall_kernel_rsp: AtomicU64,
+    pub(crate) syscall_user_rsp: AtomicU64,
+    index: usize,
+    apic_id: u32,
+    pub(crate) tss: &'static TssCell,
+}
+
+impl PerCpu {
+    pub(crate) fn new(index: usize, apic_id: u32, tss: &'static TssCell) -> Self {
+        PerCpu {
+            self_ptr: AtomicPtr::new(core::ptr::null_mut()),
+            syscall_kernel_rsp: AtomicU64::new(0),
+            syscall_user_rsp: AtomicU64::new(0),
+            index,
+            apic_id,
+            tss,
+        }
+    }
+
+    /// Index of the CPU in boot order, 0 for the bootstrap processor.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// ID of the CPU's local APIC.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+}
+
+/// Installs the bootstrap processor's per-CPU data. Must be called after
+/// `gdt::init`.
+pub fn init() {
+    install(&BOOT_CPU);
+}
+
+/// Points the current CPU's GS base at `cpu`.
+pub(crate) fn install(cpu: &'static PerCpu) {
+    cpu.self_ptr
+        .store(cpu as *const PerCpu as *mut PerCpu, Ordering::SeqCst);
+    GsBase::write(VirtAddr::from_ptr(cpu));
+    KernelGsBase::write(VirtAddr::zero());
+    INSTALLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns the current CPU's data.
+///
+/// Panics if `init` has not been called yet.
+pub fn current() -> &'static PerCpu {
+    try_current().expect("per-CPU data not initialized")
+}
+
+/// Returns the current CPU's data, or `None` before `init`.
+pub fn try_current() -> Option<&'static PerCpu> {
+    if !INSTALLED.load(Ordering::SeqCst) {
+        return None;
+    }
+    let cpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, preserves_flags, readonly));
+        Some(&*cpu)
+    }
+}
+
+/// Swaps in the kernel's GS base if an interrupt handler was entered from
+/// ring 3, i.e. if the RPL of the interrupted `code_segment` is 3, and swaps
+/// the user program's GS base back when the returned guard is dropped.
+///
+/// Must be called first in the handler, before anything uses per-CPU data.
+pub fn enter_interrupt(code_segment: u64) -> KernelGsGuard {
+    let from_user = code_segment & 3 == 3;
+    if from_user {
+        unsafe { GS::swap() };
+    }
+    KernelGsGuard { from_user }
+}
+
+/// Keeps the kernel's GS base installed while a handler runs, see
+/// `enter_interrupt`.
+pub struct KernelGsGuard {
+    from_user: bool,
+}
+
+impl Drop for KernelGsGuard {
+    fn drop(&mut self) {
+        if self.from_user {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+// CPUID 1 的 EBX 第 24-31 位是初始 APIC ID, 不需要映射 local APIC 的寄存器
+fn cpuid_apic_id() -> u32 {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    cpuid.ebx >> 24
+}
+
+#[test_case]
+fn test_boot_cpu() {
+    let cpu = current();
+    assert_eq!(cpu.index(), 0);
+    assert_eq!(cpu.apic_id(), cpuid_apic_id());
+    assert_eq!(GsBase::read(), VirtAddr::from_ptr(cpu));
+}
+
@@ -0,0 +1,228 @@
+//! 关机和重启.
+//!
+//! 关机通过 FADT 中的 PM1 控制寄存器进入 S5 睡眠状态, 需要的 SLP_TYP 值从 DSDT 的 `_S5_`
+//! 对象中读出. 重启依次尝试 ACPI 重置寄存器, 8042 键盘控制器的重置线和三重错误.
+
+use crate::acpi::{self, GenericAddress};
+use crate::memory::phys_to_virt;
+use crate::println;
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::PhysAddr;
+
+// PM1 控制寄存器中的位
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+// 等待固件切换到 ACPI 模式的最大次数
+const ACPI_ENABLE_TIMEOUT: usize = 1_000_000;
+
+const ACPI_PCI_CONFIG_SPACE: u8 = 2;
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xfe;
+// 等待 8042 输入缓冲区变空的最大次数, 超时后仍然发送重置命令
+const KEYBOARD_CONTROLLER_TIMEOUT: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// `acpi::init` failed or has not been called.
+    NoAcpiTables,
+    /// There is no FADT, or it has no PM1a control block.
+    NoPm1aControlBlock,
+    /// The DSDT has no usable `_S5_` package.
+    NoS5SleepType,
+    /// The firmware did not switch to ACPI mode.
+    AcpiEnableTimeout,
+}
+
+/// Powers the machine off through ACPI. If that fails the reason is printed
+/// and the CPU is halted with interrupts disabled.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    match acpi_shutdown() {
+        // 写入 SLP_EN 之后机器应该已经关闭了
+        Ok(()) => println!("ACPI shutdown did not power off the machine"),
+        Err(err) => println!("ACPI shutdown failed: {:?}", err),
+    }
+    halt()
+}
+
+/// Resets the machine, trying the ACPI reset register, then the 8042 reset
+/// line, then a triple fault.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Some(fadt) = acpi::tables().and_then(|tables| tables.fadt) {
+        if let Some(register) = fadt.reset_register {
+            unsafe { write_reset_register(register, fadt.reset_value) };
+        }
+    }
+    unsafe { keyboard_controller_reset() };
+    unsafe { triple_fault() }
+}
+
+fn acpi_shutdown() -> Result<(), PowerError> {
+    let tables = acpi::tables().ok_or(PowerError::NoAcpiTables)?;
+    let fadt = tables.fadt.ok_or(PowerError::NoPm1aControlBlock)?;
+    if fadt.pm1a_control_block == 0 {
+        return Err(PowerError::NoPm1aControlBlock);
+    }
+    let dsdt = tables.find(b"DSDT").ok_or(PowerError::NoS5SleepType)?;
+    let (slp_typ_a, slp_typ_b) = s5_sleep_type(dsdt.bytes()).ok_or(PowerError::NoS5SleepType)?;
+
+    let mut pm1a = Port::<u16>::new(fadt.pm1a_control_block as u16);
+    unsafe {
+        // 固件还在 legacy 模式时, 先通过 SMI 命令端口切换到 ACPI 模式
+        if pm1a.read() & SCI_EN == 0 && fadt.smi_command_port != 0 && fadt.acpi_enable != 0 {
+            Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable);
+            let mut enabled = false;
+            for _ in 0..ACPI_ENABLE_TIMEOUT {
+                if pm1a.read() & SCI_EN != 0 {
+                    enabled = true;
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            if !enabled {
+                return Err(PowerError::AcpiEnableTimeout);
+            }
+        }
+
+        let value = pm1a.read();
+        pm1a.write(
+            value & !(0b111 << SLP_TYP_SHIFT) | u16::from(slp_typ_a) << SLP_TYP_SHIFT | SLP_EN,
+        );
+        if fadt.pm1b_control_block != 0 {
+            let mut pm1b = Port::<u16>::new(fadt.pm1b_control_block as u16);
+            let value = pm1b.read();
+            pm1b.write(
+                value & !(0b111 << SLP_TYP_SHIFT) | u16::from(slp_typ_b) << SLP_TYP_SHIFT | SLP_EN,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// 在 DSDT 的 AML 代码中找到 `Name (_S5_, Package () { SLP_TYPa, SLP_TYPb, ... })`.
+///
+/// 这里不实现 AML 解释器, 只识别这一种固定的字节序列.
+fn s5_sleep_type(aml: &[u8]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0a;
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+    // 名字前面是 NameOp, 或者 NameOp 加上表示根作用域的 '\'
+    let is_name = match position {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => {
+            aml[position - 1] == NAME_OP
+                || (aml[position - 2] == NAME_OP && aml[position - 1] == b'\\')
+        }
+    };
+    if !is_name {
+        return None;
+    }
+
+    let mut rest = aml.get(position + 4..)?;
+    if *rest.first()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLength 第一个字节的第 6-7 位是后面还有几个字节
+    let pkg_length_bytes = 1 + usize::from(rest.get(1)? >> 6);
+    // 跳过 PackageOp, PkgLength 和 NumElements
+    rest = rest.get(1 + pkg_length_bytes + 1..)?;
+
+    let mut read_integer = || -> Option<u8> {
+        let (value, len) = match *rest.first()? {
+            BYTE_PREFIX => (*rest.get(1)?, 2),
+            ZERO_OP => (0, 1),
+            ONE_OP => (1, 1),
+            _ => return None,
+        };
+        rest = &rest[len..];
+        Some(value)
+    };
+    let slp_typ_a = read_integer()?;
+    let slp_typ_b = read_integer()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+unsafe fn write_reset_register(register: GenericAddress, value: u8) {
+    match register.address_space {
+        GenericAddress::SYSTEM_IO => Port::<u8>::new(register.address as u16).write(value),
+        GenericAddress::SYSTEM_MEMORY => {
+            let ptr = phys_to_virt(PhysAddr::new(register.address)).as_mut_ptr::<u8>();
+            ptr.write_volatile(value);
+        }
+        ACPI_PCI_CONFIG_SPACE => {
+            // 地址的第 32-47 位是设备, 第 16-31 位是功能, 第 0-15 位是偏移, 总线总是 0
+            let device = (register.address >> 32) as u32 & 0x1f;
+            let function = (register.address >> 16) as u32 & 0x7;
+            let offset = register.address as u32 & 0xff;
+            let address = 1 << 31 | device << 11 | function << 8 | offset & 0xfc;
+            Port::<u32>::new(PCI_CONFIG_ADDRESS).write(address);
+            Port::<u8>::new(PCI_CONFIG_DATA + (offset & 3) as u16).write(value);
+        }
+        _ => {}
+    }
+}
+
+/// 通过 8042 键盘控制器拉低 CPU 的 reset 线
+unsafe fn keyboard_controller_reset() {
+    let mut status = Port::<u8>::new(KEYBOARD_CONTROLLER_STATUS);
+    // 等待输入缓冲区为空
+    for _ in 0..KEYBOARD_CONTROLLER_TIMEOUT {
+        if status.read() & KEYBOARD_CONTROLLER_INPUT_FULL == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    Port::<u8>::new(KEYBOARD_CONTROLLER_COMMAND).write(KEYBOARD_CONTROLLER_RESET);
+}
+
+/// 加载一个空的 IDT 再触发异常, CPU 找不到 double fault 处理函数就会重置
+unsafe fn triple_fault() -> ! {
+    use x86_64::instructions::tables::lidt;
+    use x86_64::structures::DescriptorTablePointer;
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    lidt(&empty);
+    core::arch::asm!("int3");
+    halt()
+}
+
+fn halt() -> ! {
+    loop {
+        interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_s5_sleep_type() {
+    // Name (_S5_, Package (0x04) { 0x05, Zero, Zero, Zero })
+    let aml = [
+        0x10, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x07, 0x04, 0x0a, 0x05, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(s5_sleep_type(&aml[1..]), Some((5, 0)));
+    // 根作用域中的名字
+    let aml = [
+        0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x01, 0x0a, 0x07, 0x00, 0x00,
+    ];
+    assert_eq!(s5_sleep_type(&aml), Some((1, 7)));
+    // 只是引用 _S5_, 不是定义
+    assert_eq!(s5_sleep_type(b"\x70_S5_\x12\x06\x04\x00\x00"), None);
+    assert_eq!(s5_sleep_type(b"no sleep states"), None);
+}
@@ -0,0 +1,320 @@
+//! 启动其它 CPU (application processor, AP).
+//!
+//! BSP 为每个 AP 分配栈, 把 trampoline 代码复制到 1MB 以下的一个页中, 然后通过 local APIC
+//! 发送 INIT-SIPI-SIPI. AP 从这个页开始在实模式下执行, 依次切换到保护模式和长模式
+//! (使用内核的页表), 最后在自己的栈上调用 `ap_main`. AP 一个接一个地启动, 因为它们共用
+//! trampoline 中的参数.
+//!
+//! AP 只处理中断, 不能运行用户程序 (见 `ap_main`).
+
+use crate::gdt::{self, IST_STACK_COUNT};
+use crate::interrupts::{self, apic};
+use crate::memory::{self, phys_to_virt};
+use crate::percpu::{self, PerCpu};
+use crate::time::tsc;
+use crate::{acpi, println};
+use alloc::boxed::Box;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+// AP 的栈映射到与堆相同的第四级页表 slot 中 (见 apic 模块), 每个栈下面留一个不映射的保护页
+const STACKS_START: u64 = 0x_4444_6666_0000;
+const STACK_PAGES: u64 = 5;
+
+// INIT 之后等待 10ms, 每个 SIPI 之后等待 200us (Intel MultiProcessor Specification)
+const INIT_DELAY_NANOS: u64 = 10_000_000;
+const STARTUP_DELAY_NANOS: u64 = 200_000;
+const START_TIMEOUT_NANOS: u64 = 1_000_000_000;
+const START_POLL_NANOS: u64 = 1_000_000;
+
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACKS_START);
+
+#[derive(Debug)]
+pub enum SmpError {
+    /// `apic::init` has not been called, so IPIs cannot be sent.
+    ApicNotActive,
+    /// `acpi::init` has not been called, or there is no MADT.
+    NoMadt,
+    /// The frame below 1MiB reserved for the trampoline is missing or already
+    /// taken, see `BootInfoFrameAllocator::take_low_frame`.
+    NoLowFrame,
+    /// The kernel's level 4 table is above 4GiB, out of reach of 32-bit code.
+    PageTableTooHigh,
+    MappingFailed(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        SmpError::MappingFailed(error)
+    }
+}
+
+/// Returns the number of CPUs running, the bootstrap processor included.
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+// trampoline 代码读取的参数, 布局与下面汇编中的 __ap_trampoline_params 一致
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    arg: u64,
+}
+
+// 传给 ap_main 的参数, AP 设置 started 之后就不再访问它
+struct ApStart {
+    index: usize,
+    apic_id: u32,
+    interrupt_stacks: [VirtAddr; IST_STACK_COUNT],
+    privilege_stack: VirtAddr,
+    started: AtomicBool,
+}
+
+/// Starts every enabled processor listed in the MADT and logs each one that
+/// comes online. Returns the number of CPUs running afterwards.
+///
+/// Needs `acpi::init` and `apic::init`; `mapper` is used to map the
+/// trampoline page and the stacks of the new CPUs.
+pub fn init(mapper: &mut impl Mapper<Size4KiB>) -> Result<usize, SmpError> {
+    if !apic::is_active() {
+        return Err(SmpError::ApicNotActive);
+    }
+    let madt = acpi::tables()
+        .and_then(|tables| tables.madt.as_ref())
+        .ok_or(SmpError::NoMadt)?;
+
+    let boot_cpu = percpu::current();
+    println!(
+        "CPU {} online (APIC ID {}, bootstrap)",
+        boot_cpu.index(),
+        boot_cpu.apic_id()
+    );
+
+    let trampoline = prepare_trampoline(mapper)?;
+    for processor in &madt.processors {
+        let apic_id = u32::from(processor.apic_id);
+        if !processor.enabled || apic_id == boot_cpu.apic_id() {
+            continue;
+        }
+        let mut interrupt_stacks = [VirtAddr::zero(); IST_STACK_COUNT];
+        for stack in interrupt_stacks.iter_mut() {
+            *stack = allocate_stack(mapper)?;
+        }
+        let start: &'static ApStart = Box::leak(Box::new(ApStart {
+            index: online_cpus(),
+            apic_id,
+            interrupt_stacks,
+            privilege_stack: allocate_stack(mapper)?,
+            started: AtomicBool::new(false),
+        }));
+        let params = TrampolineParams {
+            cr3: Cr3::read().0.start_address().as_u64(),
+            stack_top: allocate_stack(mapper)?.as_u64(),
+            entry: ap_main as usize as u64,
+            arg: start as *const ApStart as u64,
+        };
+
+        if !unsafe { start_ap(trampoline, params, start) } {
+            // 超时的 AP 之后可能还会读取 trampoline 中的参数, 所以不再启动其它 AP
+            println!("CPU with APIC ID {} did not start", apic_id);
+            break;
+        }
+    }
+    Ok(online_cpus())
+}
+
+// 分配 trampoline 的页, 并把它映射到相同的虚拟地址: AP 开启分页时还在执行这个页中的代码
+fn prepare_trampoline(mapper: &mut impl Mapper<Size4KiB>) -> Result<PhysFrame, SmpError> {
+    if Cr3::read().0.start_address().as_u64() >= 1 << 32 {
+        return Err(SmpError::PageTableTooHigh);
+    }
+    // SIPI 只能指定 1MB 以下的页
+    let frame = memory::with_frame_allocator(|allocator| allocator.take_low_frame())
+        .ok_or(SmpError::NoLowFrame)?;
+
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match memory::with_frame_allocator(|allocator| unsafe {
+        mapper.map_to(page, frame, flags, allocator)
+    }) {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+        Err(error) => return Err(error.into()),
+    }
+    Ok(frame)
+}
+
+// 分配一个栈, 返回栈顶
+fn allocate_stack(mapper: &mut impl Mapper<Size4KiB>) -> Result<VirtAddr, SmpError> {
+    let guard = NEXT_STACK.fetch_add((STACK_PAGES + 1) * 4096, Ordering::SeqCst);
+    let bottom = Page::containing_address(VirtAddr::new(guard + 4096));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in Page::range(bottom, bottom + STACK_PAGES) {
+        memory::with_frame_allocator(|allocator| {
+            let frame = allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe { mapper.map_to(page, frame, flags, allocator) }.map(|flush| flush.flush())
+        })?;
+    }
+    Ok((bottom + STACK_PAGES).start_address())
+}
+
+// 复制 trampoline, 发送 INIT-SIPI-SIPI, 等待 AP 设置 started. 返回 AP 是否启动了
+unsafe fn start_ap(trampoline: PhysFrame, params: TrampolineParams, start: &ApStart) -> bool {
+    extern "C" {
+        static __ap_trampoline_start: u8;
+        static __ap_trampoline_params: u8;
+        static __ap_trampoline_end: u8;
+    }
+
+    // trampoline 会修改自己 (加上所在页的地址), 所以每个 AP 都重新复制一份
+    let code_start = &__ap_trampoline_start as *const u8;
+    let code_len = &__ap_trampoline_end as *const u8 as usize - code_start as usize;
+    let params_offset = &__ap_trampoline_params as *const u8 as usize - code_start as usize;
+    let page: *mut u8 = phys_to_virt(trampoline.start_address()).as_mut_ptr();
+    core::ptr::copy_nonoverlapping(code_start, page, code_len);
+    (page.add(params_offset) as *mut TrampolineParams).write_volatile(params);
+
+    let vector = (trampoline.start_address().as_u64() >> 12) as u8;
+    apic::send_init(start.apic_id);
+    tsc::delay(INIT_DELAY_NANOS);
+    // 第一个 SIPI 可能会丢失, 所以 AP 还没有启动时再发送一次
+    for _ in 0..2 {
+        apic::send_startup(start.apic_id, vector);
+        tsc::delay(STARTUP_DELAY_NANOS);
+        if start.started.load(Ordering::SeqCst) {
+            return true;
+        }
+    }
+
+    for _ in 0..START_TIMEOUT_NANOS / START_POLL_NANOS {
+        if start.started.load(Ordering::SeqCst) {
+            return true;
+        }
+        tsc::delay(START_POLL_NANOS);
+    }
+    start.started.load(Ordering::SeqCst)
+}
+
+// AP 在 trampoline 准备好的栈上, 以关闭的中断进入这里.
+// AP 不运行用户程序: 这里不调用 syscall::init (没有开启 syscall 指令), usermode 也只记录一个内核栈指针
+extern "C" fn ap_main(start: &'static ApStart) -> ! {
+    let tss = gdt::init_ap(start.interrupt_stacks, start.privilege_stack);
+    let cpu: &'static PerCpu = Box::leak(Box::new(PerCpu::new(start.index, start.apic_id, tss)));
+    percpu::install(cpu);
+    interrupts::init_idt();
+    apic::init_ap();
+
+    println!("CPU {} online (APIC ID {})", cpu.index(), cpu.apic_id());
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    start.started.store(true, Ordering::SeqCst);
+
+    // 设备中断只发给 BSP, AP 暂时只是等待
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop();
+}
+
+// trampoline 从所在页的开头执行, CS 的基址就是页的地址. 代码开始时把这个地址加到
+// GDT 指针和两个远跳转的目标上, 之后的保护模式代码通过 ebx 访问页中的数据.
+// 远跳转的内存操作数形式直接写成机器码.
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".global __ap_trampoline_start",
+    ".global __ap_trampoline_params",
+    ".global __ap_trampoline_end",
+    ".code16",
+    "__ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    xor ebx, ebx",
+    "    mov bx, ax",
+    "    shl ebx, 4",
+    "    add dword ptr [AP_GDT_BASE], ebx",
+    "    add dword ptr [AP_PROTECTED_MODE_JUMP], ebx",
+    "    add dword ptr [AP_LONG_MODE_JUMP], ebx",
+    "    lgdt [AP_GDT_POINTER]",
+    "    mov eax, cr0",
+    "    or eax, 1",
+    "    mov cr0, eax",
+    // jmp far dword ptr [ap_protected_mode_jump]
+    "    .byte 0x66, 0xff, 0x2e",
+    "    .word AP_PROTECTED_MODE_JUMP",
+    ".code32",
+    "ap_protected_mode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    // CR4.PAE
+    "    mov eax, cr4",
+    "    or eax, 1 << 5",
+    "    mov cr4, eax",
+    "    mov eax, [ebx + AP_CR3]",
+    "    mov cr3, eax",
+    // EFER.LME 和 EFER.NXE (内核的页表使用了 NO_EXECUTE)
+    "    mov ecx, 0xc0000080",
+    "    rdmsr",
+    "    or eax, (1 << 8) | (1 << 11)",
+    "    wrmsr",
+    // CR0.PG 和 CR0.WP
+    "    mov eax, cr0",
+    "    or eax, (1 << 31) | (1 << 16)",
+    "    mov cr0, eax",
+    // jmp far dword ptr [ebx + ap_long_mode_jump]
+    "    .byte 0xff, 0xab",
+    "    .long AP_LONG_MODE_JUMP",
+    ".code64",
+    "ap_long_mode:",
+    "    mov rsp, [rip + ap_stack_top]",
+    "    mov rdi, [rip + ap_arg]",
+    "    mov rax, [rip + ap_entry]",
+    "    call rax",
+    "2:",
+    "    hlt",
+    "    jmp 2b",
+    ".align 8",
+    "ap_gdt:",
+    "    .quad 0",
+    "    .quad 0x00cf9a000000ffff", // 0x08: 32 位代码段
+    "    .quad 0x00cf92000000ffff", // 0x10: 数据段
+    "    .quad 0x00af9a000000ffff", // 0x18: 64 位代码段
+    "ap_gdt_pointer:",
+    "    .word ap_gdt_pointer - ap_gdt - 1",
+    "    .long ap_gdt - __ap_trampoline_start",
+    "ap_protected_mode_jump:",
+    "    .long ap_protected_mode - __ap_trampoline_start",
+    "    .word 0x08",
+    "ap_long_mode_jump:",
+    "    .long ap_long_mode - __ap_trampoline_start",
+    "    .word 0x18",
+    ".align 8",
+    "__ap_trampoline_params:",
+    "ap_cr3:",
+    "    .quad 0",
+    "ap_stack_top:",
+    "    .quad 0",
+    "ap_entry:",
+    "    .quad 0",
+    "ap_arg:",
+    "    .quad 0",
+    "__ap_trampoline_end:",
+    // 相对于页开头的偏移, Intel 语法的内存操作数中只能出现一个符号
+    ".set AP_GDT_POINTER, ap_gdt_pointer - __ap_trampoline_start",
+    ".set AP_GDT_BASE, AP_GDT_POINTER + 2",
+    ".set AP_PROTECTED_MODE_JUMP, ap_protected_mode_jump - __ap_trampoline_start",
+    ".set AP_LONG_MODE_JUMP, ap_long_mode_jump - __ap_trampoline_start",
+    ".set AP_CR3, ap_cr3 - __ap_trampoline_start",
+    ".popsection",
+);
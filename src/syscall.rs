@@ -0,0 +1,279 @@
+//! 通过 `syscall`/`sysret` 指令实现的系统调用.
+//!
+//! 调用约定和 Linux 相同: `rax` 是调用号, 参数依次放在 `rdi`, `rsi`, `rdx`, `r10`, `r8`, `r9`,
+//! 返回值放在 `rax`. 出错时返回负的错误码 (见 `SyscallError`). 除了 `rcx` 和 `r11`
+//! (被 `syscall` 指令用来保存返回地址和 RFLAGS) 以外, 其它寄存器都不会被改变.
+
+use crate::gdt;
+use crate::memory;
+use crate::percpu;
+use crate::time::Instant;
+use crate::usermode;
+use crate::{print, serial_print};
+use core::arch::global_asm;
+use core::convert::TryFrom;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// `write(fd, buf, len)`: writes `len` bytes to the VGA buffer (fd 1) or the
+/// serial port (fd 2). Returns the number of bytes written.
+pub const SYS_WRITE: u64 = 0;
+/// `exit(code)`: terminates the calling program.
+pub const SYS_EXIT: u64 = 1;
+/// `yield()`: gives up the CPU until the next interrupt.
+pub const SYS_YIELD: u64 = 2;
+/// `sleep(millis)`: blocks for at least `millis` milliseconds.
+pub const SYS_SLEEP: u64 = 3;
+
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Errors returned to user programs, negated, in `rax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// The call number is not in the table.
+    NoSuchCall = 1,
+    /// A pointer argument is not accessible to the caller.
+    BadAddress = 2,
+    /// The file descriptor is not 1 or 2.
+    BadFileDescriptor = 3,
+    /// The text passed to `write` is not valid UTF-8.
+    InvalidArgument = 4,
+}
+
+impl SyscallError {
+    /// Returns the value the caller sees in `rax`.
+    pub fn as_return_value(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+}
+
+type SyscallHandler = fn(&SyscallFrame) -> Result<u64, SyscallError>;
+
+// 系统调用表, 下标就是调用号
+static SYSCALL_TABLE: [SyscallHandler; 4] = [sys_write, sys_exit, sys_yield, sys_sleep];
+
+/// The registers of the caller, as saved by the syscall entry.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// RFLAGS of the caller.
+    pub r11: u64,
+    /// Return address of the caller.
+    pub rcx: u64,
+    pub rsp: u64,
+}
+
+const KERNEL_STACK_SIZE: usize = 4096 * 5;
+
+// syscall 入口和 call 之间不调整栈, 所以栈顶必须 16 字节对齐
+#[repr(align(16))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
+
+// BSP 进入系统调用时切换到这个栈. 栈指针保存在 per-CPU 数据中, 见 percpu::PerCpu
+static mut KERNEL_STACK: KernelStack = KernelStack([0; KERNEL_STACK_SIZE]);
+
+extern "C" {
+    fn __syscall_entry();
+}
+
+/// Enables the `syscall` instruction on the bootstrap processor and points
+/// it at the syscall entry.
+///
+/// Must be called after `gdt::init` and `percpu::init`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    unsafe {
+        let stack_start = VirtAddr::from_ptr(&KERNEL_STACK);
+        set_kernel_stack(stack_start + KERNEL_STACK_SIZE);
+
+        Star::write(
+            selectors.user_code_selector,
+            selectors.user_data_selector,
+            selectors.cs_selector,
+            selectors.kernel_data_selector,
+        )
+        .expect("GDT layout does not match what syscall/sysret expect");
+        LStar::write(VirtAddr::new(__syscall_entry as usize as u64));
+        // 进入内核时关中断, 清除单步和方向标志
+        SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// Sets the stack the syscall entry switches to on the current CPU.
+///
+/// # Safety
+///
+/// `stack_top` must be the top of a stack that is not used by anything else
+/// while a system call is running.
+pub unsafe fn set_kernel_stack(stack_top: VirtAddr) {
+    percpu::current()
+        .syscall_kernel_rsp
+        .store(stack_top.as_u64(), Ordering::Relaxed);
+}
+
+/// Runs the system call `number` with `args` as if it was invoked with the
+/// `syscall` instruction, and returns the value the caller would see in `rax`.
+pub fn dispatch(number: u64, args: [u64; 6]) -> u64 {
+    let frame = SyscallFrame {
+        rax: number,
+        rdi: args[0],
+        rsi: args[1],
+        rdx: args[2],
+        r10: args[3],
+        r8: args[4],
+        r9: args[5],
+        ..SyscallFrame::default()
+    };
+    call(&frame)
+}
+
+fn call(frame: &SyscallFrame) -> u64 {
+    let handler = usize::try_from(frame.rax)
+        .ok()
+        .and_then(|number| SYSCALL_TABLE.get(number));
+    let result = match handler {
+        Some(handler) => handler(frame),
+        None => Err(SyscallError::NoSuchCall),
+    };
+    match result {
+        Ok(value) => value,
+        Err(error) => error.as_return_value(),
+    }
+}
+
+#[no_mangle]
+extern "C" fn __syscall_dispatch(frame: &SyscallFrame) -> u64 {
+    // sysret 到非规范地址时会在 ring 0 引发 #GP, 而栈已经是用户的栈了
+    if VirtAddr::try_new(frame.rcx).is_err() {
+        usermode::exit(-1);
+    }
+    let value = call(frame);
+    // handler 可能开了中断, sysret 之前的几条指令还在用内核栈
+    x86_64::instructions::interrupts::disable();
+    value
+}
+
+fn sys_write(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    let (fd, buf, len) = (frame.rdi, frame.rsi, frame.rdx);
+    if fd != STDOUT && fd != STDERR {
+        return Err(SyscallError::BadFileDescriptor);
+    }
+    let start = VirtAddr::try_new(buf).map_err(|_| SyscallError::BadAddress)?;
+    if !memory::is_user_accessible(start, len, false) {
+        return Err(SyscallError::BadAddress);
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), len as usize) };
+    let text = core::str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    if fd == STDOUT {
+        print!("{}", text);
+    } else {
+        serial_print!("{}", text);
+    }
+    Ok(len)
+}
+
+fn sys_exit(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    usermode::exit(frame.rdi as i64)
+}
+
+fn sys_yield(_frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    x86_64::instructions::interrupts::enable_and_hlt();
+    Ok(0)
+}
+
+fn sys_sleep(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    let deadline = Instant::now() + Duration::from_millis(frame.rdi);
+    while Instant::now() < deadline {
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+    Ok(0)
+}
+
+// syscall 不切换栈: 先把用户的 rsp 存起来, 换成内核栈, 再把用户的寄存器压栈组成 SyscallFrame.
+// 压入 10 项之后内核栈依然是 16 字节对齐的. syscall 只会从 ring 3 进入, 所以总是用 swapgs 换成内核的
+// GS 基址, sysret 之前再换回来 (见 percpu 模块). gs:[8] 和 gs:[16] 是 PerCpu 中的两个栈指针.
+global_asm!(
+    ".global __syscall_entry",
+    "__syscall_entry:",
+    "    swapgs",
+    "    mov gs:[16], rsp",
+    "    mov rsp, gs:[8]",
+    "    push qword ptr gs:[16]",
+    "    push rcx",
+    "    push r11",
+    "    push r9",
+    "    push r8",
+    "    push r10",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rax",
+    "    mov rdi, rsp",
+    "    call __syscall_dispatch",
+    "    add rsp, 8",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop r10",
+    "    pop r8",
+    "    pop r9",
+    "    pop r11",
+    "    pop rcx",
+    "    pop rsp",
+    "    swapgs",
+    "    sysretq",
+);
+
+#[test_case]
+fn test_unknown_call() {
+    assert_eq!(
+        dispatch(1000, [0; 6]),
+        SyscallError::NoSuchCall.as_return_value()
+    );
+}
+
+#[test_case]
+fn test_write_checks_arguments() {
+    let text = "kernel memory";
+    let buf = text.as_ptr() as u64;
+    let len = text.len() as u64;
+    assert_eq!(
+        dispatch(SYS_WRITE, [7, buf, len, 0, 0, 0]),
+        SyscallError::BadFileDescriptor.as_return_value()
+    );
+    assert_eq!(dispatch(SYS_WRITE, [STDOUT, buf, 0, 0, 0, 0]), 0);
+}
+
+#[test_case]
+fn test_yield_and_sleep() {
+    assert_eq!(dispatch(SYS_YIELD, [0; 6]), 0);
+    let start = Instant::now();
+    assert_eq!(dispatch(SYS_SLEEP, [3, 0, 0, 0, 0, 0]), 0);
+    assert!(start.elapsed() >= Duration::from_millis(3));
+}
+
+#[test_case]
+fn test_kernel_stack_in_per_cpu() {
+    // 汇编代码假定的 PerCpu 布局
+    let rsp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[8]", out(reg) rsp, options(nostack, preserves_flags, readonly));
+    }
+    assert_eq!(rsp, percpu::current().syscall_kernel_rsp.load(Ordering::Relaxed));
+    assert_eq!(rsp % 16, 0);
+    assert_ne!(rsp, 0);
+}
@@ -1,9 +1,13 @@
 use core::task::{Context, Poll, Waker};
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{collections::BTreeMap, sync::{Arc, Weak}, task::Wake};
 use crossbeam_queue::ArrayQueue;
 
-use super::{Task, TaskId};
+use super::{JoinHandle, Priority, Task, TaskId};
+
+/// How many tasks of higher priorities may run while a ready task of a lower
+/// priority waits, before the executor runs the waiting one.
+pub const STARVATION_LIMIT: usize = 8;
 
 struct TaskWaker {
     task_id: TaskId,
@@ -32,50 +36,123 @@ impl Wake for TaskWaker {
     }
 }
 
+/// A cloneable handle for adding tasks to an `Executor`, also while it is
+/// running (e.g. from inside one of its tasks).
+#[derive(Clone)]
+pub struct Spawner {
+    // executor 被 drop 之后不能再添加任务, 否则任务会留在没有人处理的队列中
+    spawn_queue: Weak<ArrayQueue<Task>>,
+}
+
+impl Spawner {
+    /// Queues `task`; the executor picks it up the next time it looks for
+    /// ready tasks. The returned handle resolves to the task's output.
+    ///
+    /// Panics if the executor was dropped.
+    pub fn spawn<T: Send + 'static>(&self, task: Task<T>) -> JoinHandle<T> {
+        let spawn_queue = self.spawn_queue.upgrade().expect("executor was dropped");
+        let (task, handle) = task.into_joinable();
+        if spawn_queue.push(task).is_err() {
+            panic!("spawn queue full");
+        }
+        handle
+    }
+
+    /// Returns whether the executor was dropped.
+    pub fn is_closed(&self) -> bool {
+        self.spawn_queue.strong_count() == 0
+    }
+}
+
 pub struct Executor {
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    // 每个优先级一个就绪队列, 下标是 Priority 的值
+    task_queues: [Arc<ArrayQueue<TaskId>>; Priority::COUNT],
+    // 每个优先级的队列非空时, 连续有多少个更高优先级的任务先运行了
+    skipped: [usize; Priority::COUNT],
+    // Spawner 添加的任务先放在这里, 由 executor 移到 tasks 中
+    spawn_queue: Arc<ArrayQueue<Task>>,
     wakers: BTreeMap<TaskId, Waker>,
     tasks: BTreeMap<TaskId, Task>,
 }
 
 impl Executor {
     pub fn new() -> Self {
-        let task_queue = Arc::new(ArrayQueue::new(100));
+        let task_queues = [
+            Arc::new(ArrayQueue::new(100)),
+            Arc::new(ArrayQueue::new(100)),
+            Arc::new(ArrayQueue::new(100)),
+        ];
+        let spawn_queue = Arc::new(ArrayQueue::new(100));
         let wakers = BTreeMap::new();
         let tasks = BTreeMap::new();
         Self {
-            task_queue,
+            task_queues,
+            skipped: [0; Priority::COUNT],
+            spawn_queue,
             wakers,
             tasks,
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        self.tasks.insert(task_id, task);
-        self.task_queue.push(task_id).unwrap();
+    /// Adds `task` to the executor. The returned handle resolves to the
+    /// task's output.
+    pub fn spawn<T: Send + 'static>(&mut self, task: Task<T>) -> JoinHandle<T> {
+        let (task, handle) = task.into_joinable();
+        self.add_task(task);
+        handle
     }
 
+    /// Returns a handle for adding tasks while the executor is running.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: Arc::downgrade(&self.spawn_queue),
+        }
+    }
+
+    /// Runs the tasks forever. The executor's spawner becomes the global one
+    /// used by `task::spawn`.
     pub fn run(&mut self) {
+        super::set_global_spawner(self.spawner());
         loop {
             self.run_task();
+            self.sleep_if_idle();
+        }
+    }
+
+    // 没有就绪的任务时停机, 直到下一个中断.
+    // 检查队列时关闭中断: 否则中断可能在检查之后, hlt 之前唤醒任务, 而 CPU 仍然会停下来.
+    // sti 的效果延迟到下一条指令之后, 所以 `sti; hlt` 之间不会有中断.
+    // 其它 CPU 通过 Spawner 添加的任务不会打断 hlt, 最晚在下一个时钟中断之后运行.
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        interrupts::disable();
+        if self.task_queues.iter().all(|queue| queue.is_empty()) && self.spawn_queue.is_empty() {
+            enable_and_hlt();
+        } else {
+            interrupts::enable();
         }
     }
 
+    /// Polls ready tasks until none is left.
     pub fn run_task(&mut self) {
-        let Self {
-            tasks,
-            task_queue,
-            wakers,
-        } = self;
+        loop {
+            // 运行中的任务新添加的任务也按优先级参与调度
+            while let Ok(task) = self.spawn_queue.pop() {
+                self.add_task(task);
+            }
+            let task_id = match self.next_task() {
+                Some(task_id) => task_id,
+                None => break,
+            };
 
-        while let Ok(task_id) = task_queue.pop() {
             if let Some(task) = self.tasks.get_mut(&task_id) {
+                let task_queue = &self.task_queues[task.priority as usize];
                 let waker = self
                     .wakers
                     .entry(task_id)
                     .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()).to_waker());
-                let mut context = Context::from_waker(&waker);
+                let mut context = Context::from_waker(waker);
                 match task.poll(&mut context) {
                     Poll::Ready(()) => {
                         self.tasks.remove(&task_id);
@@ -86,4 +163,42 @@ impl Executor {
             }
         }
     }
+
+    // 选择优先级最高的就绪任务, 除非某个更低优先级的队列已经等了 STARVATION_LIMIT 次
+    fn next_task(&mut self) -> Option<TaskId> {
+        for level in 0..Priority::COUNT {
+            if self.skipped[level] >= STARVATION_LIMIT {
+                // 已经完成的任务也可能被唤醒, 跳过不在 tasks 中的 ID, 否则计数被白白清零
+                while let Ok(task_id) = self.task_queues[level].pop() {
+                    if self.tasks.contains_key(&task_id) {
+                        self.skipped[level] = 0;
+                        return Some(task_id);
+                    }
+                }
+                self.skipped[level] = 0;
+            }
+        }
+
+        let level = (0..Priority::COUNT).find(|&level| !self.task_queues[level].is_empty())?;
+        let task_id = self.task_queues[level].pop().ok()?;
+        self.skipped[level] = 0;
+        for lower in level + 1..Priority::COUNT {
+            if !self.task_queues[lower].is_empty() {
+                self.skipped[lower] += 1;
+            }
+        }
+        Some(task_id)
+    }
+
+    fn add_task(&mut self, task: Task) {
+        let task_id = task.id;
+        let priority = task.priority;
+        assert!(
+            self.tasks.insert(task_id, task).is_none(),
+            "task with same ID already in tasks"
+        );
+        self.task_queues[priority as usize]
+            .push(task_id)
+            .expect("queue full");
+    }
 }
@@ -0,0 +1,83 @@
+//! 等待任务结束并取得它的输出.
+
+use super::TaskId;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+struct JoinState<T> {
+    output: Option<T>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+/// A future that resolves to the output of a spawned task.
+///
+/// Dropping the handle does not cancel the task, its output is discarded.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+// 任务结束时由 executor 中的任务使用, 把输出交给 JoinHandle
+pub(super) struct Completion<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+pub(super) fn channel<T>(id: TaskId) -> (JoinHandle<T>, Completion<T>) {
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        finished: false,
+        waker: None,
+    }));
+    let handle = JoinHandle {
+        id,
+        state: state.clone(),
+    };
+    (handle, Completion { state })
+}
+
+impl<T> Completion<T> {
+    pub(super) fn complete(self, output: T) {
+        // 先释放锁再唤醒, waker 可能会直接轮询 JoinHandle
+        let waker = {
+            let mut state = self.state.lock();
+            state.output = Some(output);
+            state.finished = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the id of the task, unique among all tasks.
+    pub fn id(&self) -> usize {
+        self.id.0
+    }
+
+    /// Returns whether the task has completed.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<T> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                assert!(!state.finished, "JoinHandle polled after completion");
+                state.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -8,7 +8,7 @@ use core::{
 use crossbeam_queue::ArrayQueue;
 use futures_util::{stream::StreamExt, task::AtomicWaker, Stream};
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
 use spin::Mutex;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
@@ -90,6 +90,8 @@ pub async fn keyboard_task() {
             if let Ok(Some(key_eveny)) = keyboard.add_byte(scancode) {
                 if let Some(key) = keyboard.process_keyevent(key_eveny) {
                     match key {
+                        // F1 打印中断统计
+                        DecodedKey::RawKey(KeyCode::F1) => crate::interrupts::stats::print(),
                         DecodedKey::RawKey(key) => print!("{:?}", key),
                         DecodedKey::Unicode(character) => print!("{}", character),
                     }
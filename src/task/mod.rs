@@ -4,8 +4,35 @@ use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 
 pub mod executor;
+pub mod join;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod timer;
+
+pub use executor::Spawner;
+pub use join::JoinHandle;
+
+use spin::Mutex;
+
+static GLOBAL_SPAWNER: Mutex<Option<Spawner>> = Mutex::new(None);
+
+/// Makes `spawn` add tasks through `spawner`, replacing the previous global
+/// spawner, which is returned. `Executor::run` sets its own.
+pub fn set_global_spawner(spawner: Spawner) -> Option<Spawner> {
+    GLOBAL_SPAWNER.lock().replace(spawner)
+}
+
+/// Spawns `task` on the executor of the global spawner.
+///
+/// Panics if no global spawner was set or its executor was dropped.
+pub fn spawn<T: Send + 'static>(task: Task<T>) -> JoinHandle<T> {
+    // 先复制一份再添加任务, 不在持有锁的时候 panic
+    let spawner = GLOBAL_SPAWNER.lock().clone();
+    match spawner {
+        Some(spawner) if !spawner.is_closed() => spawner.spawn(task),
+        _ => panic!("no global spawner, is the executor running?"),
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(usize);
@@ -16,22 +43,76 @@ impl TaskId {
     }
 }
 
-pub struct Task {
-    future: Pin<Box<dyn Future<Output = ()>>>,
+/// Scheduling class of a task. The executor runs ready tasks of a higher
+/// priority first, but lets a waiting lower priority task run after
+/// `executor::STARVATION_LIMIT` tasks of higher priorities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Deferred work of interrupt handlers, e.g. processing scancodes.
+    Interrupt = 0,
+    /// Tasks a user waits for. The default.
+    Interactive = 1,
+    /// Work nobody waits for.
+    Background = 2,
+}
+
+impl Priority {
+    pub(crate) const COUNT: usize = 3;
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Interactive
+    }
+}
+
+/// A future to be run by an executor, producing a `T` when it completes.
+///
+/// Tasks are `Send`, so they can be handed to a running executor through a
+/// `Spawner`, e.g. from another CPU. Creating and spawning a task allocates,
+/// so interrupt handlers should wake a task instead (see `keyboard`).
+pub struct Task<T = ()> {
+    future: Pin<Box<dyn Future<Output = T> + Send>>,
     id: TaskId,
+    priority: Priority,
 }
 
-impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+impl<T> Task<T> {
+    pub fn new(future: impl Future<Output = T> + Send + 'static) -> Task<T> {
         Task {
             future: Box::pin(future),
-            id: TaskId(0),
+            id: TaskId::new(),
+            priority: Priority::default(),
         }
     }
+
+    /// Sets the priority the task is scheduled with.
+    pub fn with_priority(mut self, priority: Priority) -> Task<T> {
+        self.priority = priority;
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+impl<T: Send + 'static> Task<T> {
+    // 把输出交给 JoinHandle, 得到一个输出为 () 的同 id 任务, executor 只需要保存这一种任务
+    fn into_joinable(self) -> (Task, JoinHandle<T>) {
+        let (handle, completion) = join::channel(self.id);
+        let future = self.future;
+        let task = Task {
+            future: Box::pin(async move { completion.complete(future.await) }),
+            id: self.id,
+            priority: self.priority,
+        };
+        (task, handle)
+    }
 }
 
-impl Task {
-    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+impl<T> Task<T> {
+    fn poll(&mut self, context: &mut Context) -> Poll<T> {
         self.future.as_mut().poll(context)
     }
 }
@@ -0,0 +1,196 @@
+//! 异步定时器: `sleep`, `sleep_until` 和 `interval`.
+//!
+//! 等待中的定时器按截止时间保存在一个有序表中, 每次时钟中断都会唤醒所有已经到期的定时器.
+//! 每个 future 只注册一次, 之后的 poll 原地更新 waker, drop 时注销. 表项只在任务中
+//! 插入和删除, 时钟中断只调用 `wake_by_ref`, 不会在中断中释放 waker.
+
+use crate::interrupts::{self, InterruptIndex};
+use crate::time::Instant;
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::Stream;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// 截止时间相同的定时器用注册编号区分
+type TimerKey = (Instant, u64);
+
+struct TimerEntry {
+    waker: Waker,
+    /// 中断已经唤醒过它, 在下一次 poll 之前不再重复唤醒
+    woken: bool,
+}
+
+lazy_static! {
+    static ref TIMERS: Mutex<BTreeMap<TimerKey, TimerEntry>> = Mutex::new(BTreeMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Hooks the timer futures into the timer interrupt. Must be called after
+/// `time::init`, so that the uptime is already updated when timers are checked.
+pub fn init() {
+    interrupts::register_irq(InterruptIndex::Timer.irq_line(), wake_expired)
+        .expect("registering timer wheel failed");
+}
+
+/// Returns the number of timers waiting for their deadline.
+pub fn pending() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| TIMERS.lock().len())
+}
+
+/// 在时钟中断中运行, 唤醒所有到期的定时器
+fn wake_expired() {
+    let now = Instant::now();
+    // 中断发生时如果任务正持有锁 (不应该, 它关闭了中断), 宁可推迟到下一个 tick 也不要死锁
+    if let Some(mut timers) = TIMERS.try_lock() {
+        for (_, entry) in timers.range_mut(..=(now, u64::MAX)) {
+            // 表项留给任务自己删除, 这里只借用 waker
+            if !entry.woken {
+                entry.woken = true;
+                entry.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// 一个 future 在 TIMERS 中的表项, drop 时注销
+#[derive(Default)]
+struct Registration {
+    key: Option<TimerKey>,
+}
+
+impl Registration {
+    /// Waits until `deadline` has passed. The resolution is one timer tick.
+    fn poll(&mut self, deadline: Instant, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            match self.key {
+                Some(key) if key.0 == deadline => {
+                    let entry = timers.get_mut(&key).expect("registered timer vanished");
+                    if !entry.waker.will_wake(cx.waker()) {
+                        entry.waker = cx.waker().clone();
+                    }
+                    entry.woken = false;
+                }
+                _ => {
+                    if let Some(key) = self.key.take() {
+                        timers.remove(&key);
+                    }
+                    let key = (deadline, NEXT_ID.fetch_add(1, Ordering::Relaxed));
+                    let entry = TimerEntry {
+                        waker: cx.waker().clone(),
+                        woken: false,
+                    };
+                    timers.insert(key, entry);
+                    self.key = Some(key);
+                }
+            }
+        });
+        // 第一次检查之后, 注册之前到期的 tick 不会再唤醒我们, 所以注册之后要再检查一次
+        if Instant::now() >= deadline {
+            self.cancel();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn cancel(&mut self) {
+        if let Some(key) = self.key.take() {
+            // 先取出来, 在开中断之后再释放 waker
+            let entry =
+                x86_64::instructions::interrupts::without_interrupts(|| TIMERS.lock().remove(&key));
+            drop(entry);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Future returned by `sleep` and `sleep_until`.
+pub struct Sleep {
+    deadline: Instant,
+    registration: Registration,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let deadline = self.deadline;
+        self.registration.poll(deadline, cx)
+    }
+}
+
+/// Waits for `duration` to pass.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Waits until `deadline`.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        registration: Registration::default(),
+    }
+}
+
+/// Stream returned by `interval`.
+pub struct Interval {
+    next: Instant,
+    period: Duration,
+    registration: Registration,
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        let next = self.next;
+        match self.registration.poll(next, cx) {
+            Poll::Ready(()) => {
+                let deadline = self.next;
+                // 错过的 tick 会连续产生, 保证平均周期不变
+                self.next = deadline + self.period;
+                Poll::Ready(Some(deadline))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (usize::MAX, None)
+    }
+}
+
+/// Yields an `Instant` every `period`, starting one period from now.
+pub fn interval(period: Duration) -> Interval {
+    assert!(
+        period > Duration::from_secs(0),
+        "interval period must not be zero"
+    );
+    Interval {
+        next: Instant::now() + period,
+        period,
+        registration: Registration::default(),
+    }
+}
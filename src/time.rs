@@ -0,0 +1,162 @@
+//! 基于 PIT (Programmable Interval Timer, 8253/8254) 的时钟: 全局的 tick 计数和开机以来的时间.
+
+use crate::interrupts::{self, InterruptIndex};
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+pub mod hpet;
+pub mod rtc;
+pub mod tsc;
+
+/// Input clock of the PIT in Hz.
+pub const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+
+/// Timer interrupt frequency set by `init`.
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+// 每个 tick 的长度, 由 set_frequency 根据实际的分频系数计算
+static NANOS_PER_TICK: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// Programs the PIT to `DEFAULT_FREQUENCY` and starts counting ticks.
+pub fn init() {
+    set_frequency(DEFAULT_FREQUENCY);
+    interrupts::register_irq(InterruptIndex::Timer.irq_line(), timer_tick)
+        .expect("registering timer handler failed");
+}
+
+/// Reprograms the PIT to raise the timer interrupt `hz` times per second.
+///
+/// The PIT can only divide its base clock by an integer between 1 and 65536,
+/// so the actual frequency (see `frequency`) may differ slightly. Uptime is
+/// accumulated per tick, so it stays monotonic across frequency changes.
+pub fn set_frequency(hz: u32) {
+    assert!(hz > 0, "timer frequency must not be zero");
+
+    // 分频系数 65536 写作 0
+    let divisor = (PIT_BASE_FREQUENCY / hz).max(1).min(65536);
+    let nanos_per_tick = u64::from(divisor) * 1_000_000_000 / u64::from(PIT_BASE_FREQUENCY);
+
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        // 通道 0, 先写低字节再写高字节, 模式 3 (方波), 二进制计数
+        command.write(0x36);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+        NANOS_PER_TICK.store(nanos_per_tick, Ordering::Relaxed);
+        FREQUENCY.store(PIT_BASE_FREQUENCY / divisor, Ordering::Relaxed);
+    });
+}
+
+/// Returns the actual timer interrupt frequency in Hz.
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Returns the number of timer interrupts since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since `init`, with the resolution of one tick.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NANOS.load(Ordering::Relaxed))
+}
+
+fn timer_tick() {
+    UPTIME_NANOS.fetch_add(NANOS_PER_TICK.load(Ordering::Relaxed), Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A point in time measured by the monotonic uptime clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(uptime())
+    }
+
+    /// Returns the time elapsed since `earlier`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.checked_sub(earlier.0).unwrap_or_default()
+    }
+
+    /// Returns the time elapsed since this instant was created.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns the time since `init` this instant refers to.
+    pub fn since_boot(&self) -> Duration {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs)
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0.checked_sub(rhs).unwrap_or_default())
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+#[test_case]
+fn test_ticks_advance() {
+    let start = Instant::now();
+    let ticks = ticks();
+    while self::ticks() < ticks + 5 {
+        x86_64::instructions::hlt();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(4));
+    assert!(Instant::now() > start);
+}
+
+#[test_case]
+fn test_set_frequency() {
+    set_frequency(100);
+    assert_eq!(frequency(), PIT_BASE_FREQUENCY / (PIT_BASE_FREQUENCY / 100));
+    set_frequency(DEFAULT_FREQUENCY);
+    assert_eq!(frequency(), 1000);
+}
+
+#[test_case]
+fn test_instant_arithmetic() {
+    let instant = Instant(Duration::from_millis(10));
+    assert_eq!(
+        (instant + Duration::from_millis(5)) - instant,
+        Duration::from_millis(5)
+    );
+    assert_eq!(
+        instant - Duration::from_secs(1),
+        Instant(Duration::from_secs(0))
+    );
+    assert_eq!(
+        instant.duration_since(instant + Duration::from_millis(1)),
+        Duration::from_secs(0)
+    );
+}
@@ -0,0 +1,86 @@
+//! HPET (High Precision Event Timer): 只使用它的主计数器, 作为校准 TSC 的参考时钟.
+
+use crate::acpi::{GenericAddress, Hpet};
+use crate::memory;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Size4KiB};
+
+// 紧接在 APIC 的寄存器之后
+const HPET_VIRT_ADDR: u64 = 0x_4444_5555_2000;
+
+// 寄存器的偏移
+const GENERAL_CAPABILITIES: usize = 0x00;
+const GENERAL_CONFIGURATION: usize = 0x10;
+const MAIN_COUNTER: usize = 0xf0;
+const ENABLE: u64 = 1 << 0;
+
+/// The specification requires a period of at most 100ns.
+const MAX_PERIOD_FEMTOS: u64 = 100_000_000;
+
+static PERIOD_FEMTOS: AtomicU64 = AtomicU64::new(0);
+static COUNTER_64BIT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum HpetError {
+    /// The registers are not in memory space.
+    NotMemoryMapped,
+    Map(MapToError<Size4KiB>),
+    /// The counter period reported by the HPET is out of range.
+    InvalidPeriod(u64),
+}
+
+/// Maps the registers of the HPET described by `table` and starts its main
+/// counter.
+pub fn init(mapper: &mut impl Mapper<Size4KiB>, table: &Hpet) -> Result<(), HpetError> {
+    if table.base_address.address_space != GenericAddress::SYSTEM_MEMORY {
+        return Err(HpetError::NotMemoryMapped);
+    }
+    memory::map_mmio(mapper, table.base_address.address, HPET_VIRT_ADDR).map_err(HpetError::Map)?;
+
+    unsafe {
+        let period = read_register(GENERAL_CAPABILITIES) >> 32;
+        if period == 0 || period > MAX_PERIOD_FEMTOS {
+            return Err(HpetError::InvalidPeriod(period));
+        }
+        let config = read_register(GENERAL_CONFIGURATION);
+        write_register(GENERAL_CONFIGURATION, config | ENABLE);
+        COUNTER_64BIT.store(table.counter_64bit, Ordering::Relaxed);
+        PERIOD_FEMTOS.store(period, Ordering::Release);
+    }
+    Ok(())
+}
+
+/// Returns whether `init` found a usable HPET.
+pub fn is_available() -> bool {
+    period_femtos() != 0
+}
+
+/// Returns the length of one counter tick in femtoseconds, or 0 before
+/// `init`.
+pub fn period_femtos() -> u64 {
+    PERIOD_FEMTOS.load(Ordering::Acquire)
+}
+
+/// Reads the main counter. Must only be called if `is_available`.
+pub fn read_counter() -> u64 {
+    unsafe { read_register(MAIN_COUNTER) }
+}
+
+/// Returns the ticks from `start` to `end`, taking the wrap around of a
+/// 32-bit counter into account.
+pub fn ticks_between(start: u64, end: u64) -> u64 {
+    if COUNTER_64BIT.load(Ordering::Relaxed) {
+        end.wrapping_sub(start)
+    } else {
+        u64::from((end as u32).wrapping_sub(start as u32))
+    }
+}
+
+unsafe fn read_register(offset: usize) -> u64 {
+    ((HPET_VIRT_ADDR as usize + offset) as *const u64).read_volatile()
+}
+
+unsafe fn write_register(offset: usize, value: u64) {
+    ((HPET_VIRT_ADDR as usize + offset) as *mut u64).write_volatile(value)
+}
@@ -0,0 +1,377 @@
+//! CMOS 实时时钟 (RTC): 读取日历时间, 可选地打开 IRQ 8 上的周期中断和闹钟中断.
+
+use crate::interrupts::{self, InterruptIndex, IrqError};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_ALARM_SECONDS: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_ALARM_MINUTES: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_ALARM_HOURS: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+// status A
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// status B
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const BINARY_MODE: u8 = 1 << 2;
+const HOUR_24_MODE: u8 = 1 << 1;
+// 12 小时制时小时寄存器的最高位表示下午
+const HOUR_PM: u8 = 1 << 7;
+// 等待更新结束的最大读取次数. 更新最多持续约 2ms, 一次端口读写大约 1µs;
+// 超时 (例如 RTC 坏了) 之后照样读, now() 会再读一次来确认
+const UPDATE_TIMEOUT: usize = 10_000;
+
+/// CMOS 里没有可靠的世纪寄存器 (位置要从 ACPI FADT 中读), 这里假设是 21 世纪
+const CENTURY: u16 = 2000;
+
+static PERIODIC_COUNT: AtomicU64 = AtomicU64::new(0);
+static ALARM_COUNT: AtomicU64 = AtomicU64::new(0);
+
+struct Cmos {
+    address: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.address.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.address.write(register);
+            self.data.write(value);
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0
+    }
+
+    fn read_raw(&mut self) -> [u8; 6] {
+        for _ in 0..UPDATE_TIMEOUT {
+            if !self.update_in_progress() {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        [
+            self.read(REG_SECONDS),
+            self.read(REG_MINUTES),
+            self.read(REG_HOURS),
+            self.read(REG_DAY),
+            self.read(REG_MONTH),
+            self.read(REG_YEAR),
+        ]
+    }
+}
+
+lazy_static! {
+    // 中断处理函数也要访问 CMOS, 所以其它地方都在关中断的情况下加锁
+    static ref CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+        address: Port::new(CMOS_ADDRESS),
+        data: Port::new(CMOS_DATA),
+    });
+}
+
+/// A calendar date and time as kept by the RTC (normally UTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the seconds since 1970-01-01 00:00:00.
+    pub fn unix_timestamp(&self) -> u64 {
+        // Howard Hinnant 的 days_from_civil 算法, 把 3 月当作一年的开始
+        let year = i64::from(self.year) - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = i64::from(self.month);
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        (days * 86_400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second)) as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Reads the current date and time from the RTC.
+pub fn now() -> DateTime {
+    let (raw, status_b) = without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        // 读的过程中可能刚好发生了更新, 连续两次读到相同的值才算数
+        let mut raw = cmos.read_raw();
+        loop {
+            let again = cmos.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(REG_STATUS_B))
+    });
+    let [second, minute, hour, day, month, year] = raw;
+
+    let decode = |value: u8| {
+        if status_b & BINARY_MODE != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+
+    DateTime {
+        year: CENTURY + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour: decode_hour(hour, status_b),
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Returns the current Unix timestamp in seconds.
+pub fn unix_time() -> u64 {
+    now().unix_timestamp()
+}
+
+/// Enables the periodic RTC interrupt at `32768 >> (rate - 1)` Hz.
+///
+/// `rate` must be between 3 (8192 Hz) and 15 (2 Hz).
+pub fn enable_periodic_interrupt(rate: u8) -> Result<(), IrqError> {
+    assert!((3..=15).contains(&rate), "invalid RTC rate {}", rate);
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(REG_STATUS_A);
+        cmos.write(REG_STATUS_A, (status_a & 0xf0) | rate);
+    });
+    enable_interrupt(PERIODIC_INTERRUPT)
+}
+
+pub fn disable_periodic_interrupt() {
+    disable_interrupt(PERIODIC_INTERRUPT);
+}
+
+/// Raises the RTC alarm interrupt every day at the given time.
+pub fn set_alarm(hour: u8, minute: u8, second: u8) -> Result<(), IrqError> {
+    assert!(
+        hour < 24 && minute < 60 && second < 60,
+        "invalid alarm time"
+    );
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B);
+        let encode = |value: u8| {
+            if status_b & BINARY_MODE != 0 {
+                value
+            } else {
+                to_bcd(value)
+            }
+        };
+        cmos.write(REG_ALARM_SECONDS, encode(second));
+        cmos.write(REG_ALARM_MINUTES, encode(minute));
+        cmos.write(REG_ALARM_HOURS, encode_hour(hour, status_b));
+    });
+    enable_interrupt(ALARM_INTERRUPT)
+}
+
+pub fn clear_alarm() {
+    disable_interrupt(ALARM_INTERRUPT);
+}
+
+/// Returns the number of periodic interrupts received.
+pub fn periodic_count() -> u64 {
+    PERIODIC_COUNT.load(Ordering::Relaxed)
+}
+
+/// Returns the number of alarm interrupts received.
+pub fn alarm_count() -> u64 {
+    ALARM_COUNT.load(Ordering::Relaxed)
+}
+
+fn enable_interrupt(bit: u8) -> Result<(), IrqError> {
+    let line = InterruptIndex::Rtc.irq_line();
+    let enabled = without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B);
+        cmos.write(REG_STATUS_B, status_b | bit);
+        // 清掉还没处理的中断标志, 否则 RTC 不会再发出中断
+        cmos.read(REG_STATUS_C);
+        status_b & (PERIODIC_INTERRUPT | ALARM_INTERRUPT)
+    });
+    if enabled == 0 {
+        if let Err(err) = interrupts::register_irq(line, rtc_interrupt) {
+            // 没有 handler 读状态寄存器 C, 中断打开也没有用
+            without_interrupts(|| {
+                let mut cmos = CMOS.lock();
+                let status_b = cmos.read(REG_STATUS_B);
+                cmos.write(REG_STATUS_B, status_b & !bit);
+            });
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn disable_interrupt(bit: u8) {
+    let remaining = without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(REG_STATUS_B) & !bit;
+        cmos.write(REG_STATUS_B, status_b);
+        status_b & (PERIODIC_INTERRUPT | ALARM_INTERRUPT)
+    });
+    if remaining == 0 {
+        interrupts::unregister_irq(InterruptIndex::Rtc.irq_line(), rtc_interrupt);
+    }
+}
+
+fn rtc_interrupt() {
+    // 必须读状态寄存器 C, RTC 才会发出下一次中断
+    let status_c = CMOS.lock().read(REG_STATUS_C);
+    if status_c & PERIODIC_INTERRUPT != 0 {
+        PERIODIC_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    if status_c & ALARM_INTERRUPT != 0 {
+        ALARM_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | (value % 10)
+}
+
+fn decode_hour(raw: u8, status_b: u8) -> u8 {
+    let hour = raw & !HOUR_PM;
+    let hour = if status_b & BINARY_MODE != 0 {
+        hour
+    } else {
+        from_bcd(hour)
+    };
+    if status_b & HOUR_24_MODE != 0 {
+        return hour;
+    }
+    // 12 小时制: 12 AM 是 0 点, 12 PM 是 12 点
+    if raw & HOUR_PM != 0 {
+        hour % 12 + 12
+    } else {
+        hour % 12
+    }
+}
+
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+    let encode = |value: u8| {
+        if status_b & BINARY_MODE != 0 {
+            value
+        } else {
+            to_bcd(value)
+        }
+    };
+    if status_b & HOUR_24_MODE != 0 {
+        return encode(hour);
+    }
+    let pm = if hour >= 12 { HOUR_PM } else { 0 };
+    let hour = match hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+    encode(hour) | pm
+}
+
+#[test_case]
+fn test_unix_timestamp() {
+    let epoch = DateTime {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    assert_eq!(epoch.unix_timestamp(), 0);
+    let leap_day = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 12,
+        minute: 34,
+        second: 56,
+    };
+    assert_eq!(leap_day.unix_timestamp(), 1_709_210_096);
+}
+
+#[test_case]
+fn test_hour_modes() {
+    assert_eq!(from_bcd(0x59), 59);
+    assert_eq!(to_bcd(59), 0x59);
+    // 12 小时制, BCD
+    assert_eq!(decode_hour(0x12, 0), 0);
+    assert_eq!(decode_hour(0x12 | HOUR_PM, 0), 12);
+    assert_eq!(decode_hour(0x11 | HOUR_PM, 0), 23);
+    assert_eq!(encode_hour(0, 0), 0x12);
+    assert_eq!(encode_hour(23, 0), 0x11 | HOUR_PM);
+    // 24 小时制, 二进制
+    assert_eq!(decode_hour(23, BINARY_MODE | HOUR_24_MODE), 23);
+}
+
+#[test_case]
+fn test_now() {
+    let now = now();
+    assert!(now.year >= 2020);
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn test_periodic_interrupt() {
+    let count = periodic_count();
+    // rate 6: 1024 Hz
+    enable_periodic_interrupt(6).expect("enabling RTC interrupt failed");
+    while periodic_count() < count + 3 {
+        x86_64::instructions::hlt();
+    }
+    disable_periodic_interrupt();
+    assert!(interrupts::is_irq_masked(InterruptIndex::Rtc.irq_line()));
+}
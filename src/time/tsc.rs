@@ -0,0 +1,239 @@
+//! 时间戳计数器 (TSC): 开机时用 PIT 通道 2 校准频率, 提供纳秒精度的单调时钟.
+//!
+//! HPET 需要 ACPI 表才能找到, 找到之后可以调用 `recalibrate` 用更精确的 HPET 重新校准.
+//! 没有 invariant TSC 时 (例如 QEMU 的 TCG) 仍然使用 TSC: 老的 CPU 上它在节能状态下可能会变慢
+//! 甚至停止, 单调时钟会比实际时间慢, 但仍然是单调的. `is_invariant` 报告是否有这个问题.
+
+use super::{hpet, PIT_BASE_FREQUENCY};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// bit 0: 通道 2 的 gate, bit 1: 扬声器, bit 5: 通道 2 的输出 (只读)
+const PIT_CONTROL: u16 = 0x61;
+
+/// Length of one calibration run.
+const CALIBRATION_MILLIS: u32 = 10;
+const CALIBRATION_RUNS: usize = 3;
+/// Upper bound for the reads while waiting for the reference clock. An I/O
+/// port read takes about a microsecond, so this is roughly a second.
+const CALIBRATION_TIMEOUT_READS: u32 = 1_000_000;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+// recalibrate 之前经过的纳秒数, 让单调时钟在频率改变时保持连续
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+/// Calibrates the TSC against the PIT. Takes about 30ms.
+///
+/// If the PIT does not count, the frequency stays 0 and `monotonic_nanos`
+/// falls back to the timer ticks.
+pub fn init() {
+    INVARIANT.store(check_invariant(), Ordering::Relaxed);
+
+    if let Some(frequency) = calibrate(measure_with_pit) {
+        BOOT_TSC.store(read(), Ordering::Relaxed);
+        FREQUENCY.store(frequency, Ordering::Relaxed);
+    }
+}
+
+/// Calibrates the TSC again, against the HPET if `hpet::init` found one and
+/// the PIT otherwise. `monotonic_nanos` stays continuous.
+pub fn recalibrate() {
+    let measure = if hpet::is_available() {
+        measure_with_hpet
+    } else {
+        measure_with_pit
+    };
+    if let Some(frequency) = calibrate(measure) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let now = read();
+            BASE_NANOS.store(tsc_nanos(now), Ordering::Relaxed);
+            BOOT_TSC.store(now, Ordering::Relaxed);
+            FREQUENCY.store(frequency, Ordering::Relaxed);
+        });
+    }
+}
+
+fn calibrate(measure: fn() -> Option<u64>) -> Option<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // 取最小值: 测量期间被 SMI 之类打断只会让结果偏大
+        (0..CALIBRATION_RUNS).filter_map(|_| measure()).min()
+    })
+}
+
+/// Returns whether the CPU reports an invariant TSC, i.e. one that ticks at
+/// a constant rate regardless of power states.
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Returns the calibrated TSC frequency in Hz, or 0 before `init`.
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Reads the raw time stamp counter.
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Converts a number of TSC cycles to nanoseconds.
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    let frequency = frequency();
+    if frequency == 0 {
+        return 0;
+    }
+    (u128::from(cycles) * 1_000_000_000 / u128::from(frequency)) as u64
+}
+
+/// Converts nanoseconds to a number of TSC cycles.
+pub fn nanos_to_cycles(nanos: u64) -> u64 {
+    (u128::from(nanos) * u128::from(frequency()) / 1_000_000_000) as u64
+}
+
+/// Returns the nanoseconds since `init`.
+///
+/// Falls back to the tick based `time::uptime` before the TSC is calibrated.
+/// If the TSC is not invariant (see `is_invariant`), time spent in power
+/// saving states may be missing.
+pub fn monotonic_nanos() -> u64 {
+    if frequency() == 0 {
+        return super::uptime().as_nanos() as u64;
+    }
+    tsc_nanos(read())
+}
+
+/// Busy-waits for `nanos` nanoseconds.
+///
+/// Counts TSC cycles even if the TSC is not invariant: it ticks at its
+/// nominal rate while the CPU is busy.
+pub fn delay(nanos: u64) {
+    if frequency() == 0 {
+        let deadline = super::uptime() + core::time::Duration::from_nanos(nanos);
+        while super::uptime() < deadline {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    let start = read();
+    let cycles = nanos_to_cycles(nanos);
+    while read().wrapping_sub(start) < cycles {
+        core::hint::spin_loop();
+    }
+}
+
+fn tsc_nanos(tsc: u64) -> u64 {
+    BASE_NANOS.load(Ordering::Relaxed)
+        + cycles_to_nanos(tsc.wrapping_sub(BOOT_TSC.load(Ordering::Relaxed)))
+}
+
+// CPUID.80000007H:EDX[8]
+fn check_invariant() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    unsafe {
+        if __cpuid(0x8000_0000).eax < 0x8000_0007 {
+            return false;
+        }
+        __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+}
+
+/// 让 PIT 通道 2 倒数 CALIBRATION_MILLIS 毫秒, 同时计算经过的 TSC 周期
+fn measure_with_pit() -> Option<u64> {
+    let count = PIT_BASE_FREQUENCY / 1000 * CALIBRATION_MILLIS;
+
+    let mut control: Port<u8> = Port::new(PIT_CONTROL);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel_2: Port<u8> = Port::new(PIT_CHANNEL_2);
+    unsafe {
+        // 关闭 gate 和扬声器
+        let value = control.read() & !0b11;
+        control.write(value);
+        // 通道 2, 先写低字节再写高字节, 模式 0 (计数结束时输出变高), 二进制计数
+        command.write(0xb0);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        // 打开 gate, 开始倒数
+        control.write(value | 0b1);
+        let start = read();
+        let mut reads = 0;
+        while control.read() & (1 << 5) == 0 {
+            reads += 1;
+            if reads == CALIBRATION_TIMEOUT_READS {
+                // 没有 PIT (或者通道 2 的 gate 没有接上)
+                control.write(value);
+                return None;
+            }
+        }
+        let end = read();
+        control.write(value);
+
+        Some((end - start) * u64::from(PIT_BASE_FREQUENCY) / u64::from(count))
+    }
+}
+
+/// 等待 HPET 的主计数器前进 CALIBRATION_MILLIS 毫秒, 同时计算经过的 TSC 周期
+fn measure_with_hpet() -> Option<u64> {
+    let period = hpet::period_femtos();
+    let ticks = u64::from(CALIBRATION_MILLIS) * 1_000_000_000_000 / period;
+
+    let hpet_start = hpet::read_counter();
+    let start = read();
+    let mut reads = 0;
+    let elapsed = loop {
+        let elapsed = hpet::ticks_between(hpet_start, hpet::read_counter());
+        if elapsed >= ticks {
+            break elapsed;
+        }
+        reads += 1;
+        if reads == CALIBRATION_TIMEOUT_READS {
+            return None;
+        }
+    };
+    let end = read();
+
+    let femtos = u128::from(elapsed) * u128::from(period);
+    Some((u128::from(end - start) * 1_000_000_000_000_000 / femtos) as u64)
+}
+
+#[test_case]
+fn test_tsc_calibrated() {
+    // QEMU 的 TSC 至少有几百 MHz
+    assert!(frequency() > 100_000_000);
+    let start = monotonic_nanos();
+    let ticks = super::ticks();
+    while super::ticks() < ticks + 2 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = monotonic_nanos() - start;
+    // 两次 tick 之间至少经过了一个 tick (1ms), 最多三个
+    assert!(
+        elapsed > 900_000 && elapsed < 3_100_000,
+        "elapsed {}ns",
+        elapsed
+    );
+}
+
+#[test_case]
+fn test_sub_millisecond_resolution() {
+    // 回退到 1ms 的 tick 时, 两次不同的读数至少相差 1ms
+    let start = monotonic_nanos();
+    let mut now = monotonic_nanos();
+    while now == start {
+        now = monotonic_nanos();
+    }
+    assert!(now - start < 100_000, "resolution {}ns", now - start);
+}
+
+#[test_case]
+fn test_delay() {
+    let ticks = super::ticks();
+    delay(5_000_000);
+    let elapsed = super::ticks() - ticks;
+    assert!(elapsed >= 4 && elapsed <= 6, "{} ticks", elapsed);
+}
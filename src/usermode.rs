@@ -0,0 +1,112 @@
+//! 以 ring 3 运行用户程序.
+//!
+//! `run` 保存内核的 callee-saved 寄存器和栈指针, 然后用 `iretq` 跳到用户程序. 用户程序调用
+//! `exit` 系统调用时, `exit` 恢复保存的栈指针, 看起来就像 `run` 返回了一样.
+
+use crate::gdt;
+use core::arch::global_asm;
+use x86_64::registers::model_specific::KernelGsBase;
+use x86_64::VirtAddr;
+
+// 进入用户态之前内核的栈指针, 0 表示没有正在运行的用户程序. 汇编代码通过符号名访问
+#[no_mangle]
+static mut USER_MODE_KERNEL_RSP: u64 = 0;
+
+extern "C" {
+    fn __enter_user_mode(entry: u64, stack_top: u64, code_selector: u64, data_selector: u64)
+        -> i64;
+    fn __exit_user_mode(code: i64) -> !;
+}
+
+/// Runs the code at `entry` in ring 3 with the stack pointer set to
+/// `stack_top`, and returns the code the program passes to the `exit`
+/// system call.
+///
+/// # Safety
+///
+/// `entry` and the stack must be mapped as user pages in the active address
+/// space, which must stay active until the program exits.
+pub unsafe fn run(entry: VirtAddr, stack_top: VirtAddr) -> i64 {
+    assert_eq!(USER_MODE_KERNEL_RSP, 0, "a user program is already running");
+
+    let selectors = gdt::selectors();
+    let interrupts_enabled = x86_64::instructions::interrupts::are_enabled();
+    // 新程序的 GS 基址从 0 开始, iretq 之前 swapgs 把它换到 GS 基址中
+    KernelGsBase::write(VirtAddr::zero());
+    let code = __enter_user_mode(
+        entry.as_u64(),
+        stack_top.as_u64(),
+        u64::from(selectors.user_code_selector.0),
+        u64::from(selectors.user_data_selector.0),
+    );
+    USER_MODE_KERNEL_RSP = 0;
+
+    // exit 系统调用是在关中断的情况下返回的
+    if interrupts_enabled {
+        x86_64::instructions::interrupts::enable();
+    }
+    code
+}
+
+/// Terminates the running user program, making `run` return `code`.
+///
+/// Called by the `exit` system call, with the kernel stack the program
+/// trapped onto being abandoned.
+pub fn exit(code: i64) -> ! {
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        assert_ne!(USER_MODE_KERNEL_RSP, 0, "no user program is running");
+        __exit_user_mode(code)
+    }
+}
+
+// rdi: 入口地址, rsi: 用户栈, rdx: 用户代码段, rcx: 用户数据段.
+// iretq 依次弹出 rip, cs, rflags, rsp, ss. rflags 只设置 IF (0x202, 第 1 位总是 1).
+// swapgs 之后到 iretq 之间来的中断会把用户的 GS 基址当成内核的, 所以先关中断, 由 iretq 恢复.
+global_asm!(
+    ".global __enter_user_mode",
+    ".global __exit_user_mode",
+    "__enter_user_mode:",
+    "    push rbx",
+    "    push rbp",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov [rip + USER_MODE_KERNEL_RSP], rsp",
+    "    push rcx",
+    "    push rsi",
+    "    push 0x202",
+    "    push rdx",
+    "    push rdi",
+    // 不把内核寄存器的值留给用户程序
+    "    xor eax, eax",
+    "    xor ebx, ebx",
+    "    xor ecx, ecx",
+    "    xor edx, edx",
+    "    xor esi, esi",
+    "    xor edi, edi",
+    "    xor ebp, ebp",
+    "    xor r8d, r8d",
+    "    xor r9d, r9d",
+    "    xor r10d, r10d",
+    "    xor r11d, r11d",
+    "    xor r12d, r12d",
+    "    xor r13d, r13d",
+    "    xor r14d, r14d",
+    "    xor r15d, r15d",
+    "    cli",
+    "    swapgs",
+    "    iretq",
+    // rdi: 退出码, 作为 __enter_user_mode 的返回值
+    "__exit_user_mode:",
+    "    mov rsp, [rip + USER_MODE_KERNEL_RSP]",
+    "    mov rax, rdi",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbp",
+    "    pop rbx",
+    "    ret",
+);
@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::acpi;
+use blog_os::allocator;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use blog_os::time::{hpet, tsc};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::VirtAddr;
+
+// 映射 HPET 的寄存器要用到 mapper
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    *MAPPER.lock() = Some(mapper);
+
+    test_main();
+
+    loop {}
+}
+
+#[test_case]
+fn tables_found() {
+    let tables = acpi::init().expect("ACPI tables not found");
+    assert!(tables.find(b"APIC").is_some());
+    assert!(tables.find(b"FACP").is_some());
+    // DSDT 通过 FADT 找到
+    assert!(tables.find(b"DSDT").is_some());
+    // 第二次调用返回同一份数据
+    assert!(core::ptr::eq(tables, acpi::init().unwrap()));
+    assert!(core::ptr::eq(tables, acpi::tables().unwrap()));
+}
+
+#[test_case]
+fn madt_describes_boot_cpu_and_io_apic() {
+    let madt = acpi::init().unwrap().madt.as_ref().expect("no MADT");
+    assert_eq!(madt.local_apic_address.as_u64(), 0xfee0_0000);
+    assert!(madt.processors.iter().any(|cpu| cpu.enabled));
+    assert_eq!(madt.io_apics.len(), 1);
+    assert_eq!(madt.io_apics[0].address.as_u64(), 0xfec0_0000);
+    assert_eq!(madt.io_apics[0].gsi_base, 0);
+    // QEMU 把 PIT 的 IRQ 0 接到 GSI 2
+    assert!(madt
+        .interrupt_overrides
+        .iter()
+        .any(|entry| entry.irq == 0 && entry.gsi == 2));
+    // PCI 中断 (例如 IRQ 9 上的 SCI) 是高电平有效的电平触发
+    assert!(madt
+        .interrupt_overrides
+        .iter()
+        .any(|entry| entry.irq == 9 && entry.level_triggered() && !entry.active_low()));
+}
+
+#[test_case]
+fn fadt_has_power_management_ports() {
+    let fadt = acpi::init().unwrap().fadt.expect("no FADT");
+    assert!(fadt.dsdt.is_some());
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert_ne!(fadt.sci_interrupt, 0);
+}
+
+#[test_case]
+fn hpet_recalibrates_tsc() {
+    let table = acpi::init().unwrap().hpet.expect("no HPET");
+    let mut mapper = MAPPER.lock();
+    hpet::init(mapper.as_mut().unwrap(), &table).expect("HPET initialization failed");
+    assert!(hpet::is_available());
+
+    let pit_frequency = tsc::frequency();
+    let before = tsc::monotonic_nanos();
+    tsc::recalibrate();
+    assert!(tsc::monotonic_nanos() >= before);
+    // 两个参考时钟的结果相差不超过 5%
+    let hpet_frequency = tsc::frequency();
+    let difference = pit_frequency.max(hpet_frequency) - pit_frequency.min(hpet_frequency);
+    assert!(
+        difference < pit_frequency / 20,
+        "PIT: {}Hz, HPET: {}Hz",
+        pit_frequency,
+        hpet_frequency
+    );
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use blog_os::allocator;
+use blog_os::memory::{self, AddressSpace, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Page, PageTableFlags, Translate,
+};
+use x86_64::VirtAddr;
+
+// 一个内核没有使用的第四级页表 slot 中的地址
+const USER_ADDR: u64 = 0x_3200_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+fn user_page() -> Page {
+    Page::containing_address(VirtAddr::new(USER_ADDR))
+}
+
+#[test_case]
+fn kernel_mapped_in_new_address_space() {
+    let heap_value = Box::new(42);
+    let address_space = AddressSpace::new().expect("no frame for level 4 table");
+
+    unsafe { address_space.activate() };
+    assert!(address_space.is_active());
+    assert_eq!(*heap_value, 42);
+    unsafe { memory::activate_kernel_address_space() };
+    assert!(!address_space.is_active());
+}
+
+#[test_case]
+fn user_page_isolated() {
+    let mut address_space = AddressSpace::new().expect("no frame for level 4 table");
+    address_space
+        .map_user_page(user_page(), PageTableFlags::WRITABLE)
+        .expect("map_user_page failed");
+
+    unsafe {
+        address_space.activate();
+        let ptr = USER_ADDR as *mut u64;
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+        memory::activate_kernel_address_space();
+    }
+
+    let mut other = AddressSpace::new().expect("no frame for level 4 table");
+    assert_eq!(other.mapper().translate_addr(VirtAddr::new(USER_ADDR)), None);
+}
+
+#[test_case]
+fn unmap_user_page() {
+    let mut address_space = AddressSpace::new().expect("no frame for level 4 table");
+    address_space
+        .map_user_page(user_page(), PageTableFlags::WRITABLE)
+        .expect("map_user_page failed");
+    address_space.unmap_user_page(user_page()).expect("unmap_user_page failed");
+    assert_eq!(address_space.mapper().translate_addr(VirtAddr::new(USER_ADDR)), None);
+}
+
+#[test_case]
+fn drop_frees_frames() {
+    let mut address_space = AddressSpace::new().expect("no frame for level 4 table");
+    let frame = address_space
+        .map_user_page(user_page(), PageTableFlags::WRITABLE)
+        .expect("map_user_page failed");
+    drop(address_space);
+
+    // 被释放的 frame (level 4/3/2/1 页表和数据页) 会被优先分配
+    memory::with_frame_allocator(|allocator| {
+        let frames: Vec<_> = (0..5).map(|_| allocator.allocate_frame().unwrap()).collect();
+        assert!(frames.contains(&frame));
+        for frame in frames {
+            unsafe { allocator.deallocate_frame(frame) };
+        }
+    });
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+
+use blog_os::interrupts;
+
+blog_os::exception_test!(
+    "alignment_check::raise_with_error_code",
+    "EXCEPTION: ALIGNMENT CHECK\nError Code: 0x0",
+    raise_with_error_code
+);
+
+// #AC 只在 CPL 3 且 CR0.AM 和 RFLAGS.AC 都置位时检查, 而 QEMU 的 TCG 不模拟对齐检查,
+// 所以按 CPU 的方式压入栈帧和错误码 (#AC 的错误码总是 0), 直接进入 handler
+fn raise_with_error_code() {
+    unsafe { interrupts::raise_with_error_code(17, 0) };
+}
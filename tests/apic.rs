@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::allocator;
+use blog_os::interrupts::{self, apic, InterruptIndex};
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+
+    assert!(apic::is_supported(), "QEMU always emulates an APIC");
+    apic::init(&mut mapper).expect("APIC initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[test_case]
+fn apic_replaces_pics() {
+    assert!(apic::is_active());
+    let master_mask: u8 = unsafe { Port::new(0x21).read() };
+    let slave_mask: u8 = unsafe { Port::new(0xa1).read() };
+    assert_eq!((master_mask, slave_mask), (0xff, 0xff));
+    assert!(!interrupts::is_irq_masked(InterruptIndex::Timer.irq_line()));
+    assert!(!interrupts::is_irq_masked(
+        InterruptIndex::KeyBoard.irq_line()
+    ));
+}
+
+// IRQ 2 (cascade) 在 I/O APIC 上没有 pin, 而 ISA_IRQ_TO_GSI 把它记成了 PIT 的 pin 2
+#[test_case]
+fn cascade_line_does_not_touch_timer_pin() {
+    const CASCADE_LINE: u8 = 2;
+    assert!(interrupts::is_irq_masked(CASCADE_LINE));
+    interrupts::unmask_irq(CASCADE_LINE);
+    interrupts::mask_irq(CASCADE_LINE);
+    assert!(!interrupts::is_irq_masked(InterruptIndex::Timer.irq_line()));
+}
+
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn timer_interrupts_through_io_apic() {
+    let line = InterruptIndex::Timer.irq_line();
+    interrupts::register_irq(line, count_tick).unwrap();
+    // 每次 hlt 都会被下一个中断唤醒, 没有 EOI 的话只会收到一次时钟中断
+    while TICKS.load(Ordering::SeqCst) < 3 {
+        x86_64::instructions::hlt();
+    }
+    assert!(interrupts::unregister_irq(line, count_tick));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,13 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!("bound_range_exceeded::raise_with_int", "EXCEPTION: BOUND RANGE EXCEEDED", raise_with_int);
+
+// bound 指令在 64 位模式下无效 (会引起 #UD), 只能用 int 5 直接进入 handler
+fn raise_with_int() {
+    unsafe {
+        asm!("int 5");
+    }
+}
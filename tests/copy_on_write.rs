@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::allocator;
+use blog_os::memory::{self, cow, AddressSpace, BootInfoFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Translate,
+};
+use x86_64::VirtAddr;
+
+// 内核和用户地址空间都没有使用的第四级页表 slot 中的地址
+const KERNEL_ADDR: u64 = 0x_5555_0000_0000;
+const USER_ADDR: u64 = 0x_3200_0000_0000;
+
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    *MAPPER.lock() = Some(mapper);
+
+    test_main();
+
+    loop {}
+}
+
+/// 在内核页表中映射一个新的可写页
+fn map_kernel_page(mapper: &mut OffsetPageTable, page: Page) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::with_frame_allocator(|allocator| {
+        let frame = allocator.allocate_frame().expect("out of frames");
+        unsafe { mapper.map_to(page, frame, flags, allocator).unwrap().flush() };
+    });
+}
+
+fn frame_of(mapper: &OffsetPageTable, addr: u64) -> PhysFrame {
+    let phys = mapper.translate_addr(VirtAddr::new(addr)).expect("not mapped");
+    PhysFrame::containing_address(phys)
+}
+
+#[test_case]
+fn duplicated_pages_diverge_after_write() {
+    let mut guard = MAPPER.lock();
+    let mapper = guard.as_mut().unwrap();
+    let src = Page::containing_address(VirtAddr::new(KERNEL_ADDR));
+    let dst = src + 1;
+    map_kernel_page(mapper, src);
+
+    let src_ptr = src.start_address().as_mut_ptr::<u64>();
+    let dst_ptr = dst.start_address().as_mut_ptr::<u64>();
+    unsafe {
+        src_ptr.write_volatile(1);
+        cow::duplicate_page(mapper, src, dst).expect("duplicate_page failed");
+        assert_eq!(dst_ptr.read_volatile(), 1);
+        assert_eq!(frame_of(mapper, KERNEL_ADDR), frame_of(mapper, dst.start_address().as_u64()));
+
+        dst_ptr.write_volatile(2);
+        assert_eq!(src_ptr.read_volatile(), 1);
+        assert_eq!(dst_ptr.read_volatile(), 2);
+        assert_ne!(frame_of(mapper, KERNEL_ADDR), frame_of(mapper, dst.start_address().as_u64()));
+
+        // 另一个映射已经复制走了, 原来的页不需要再复制
+        let frame = frame_of(mapper, KERNEL_ADDR);
+        assert_eq!(memory::with_frame_allocator(|allocator| allocator.ref_count(frame)), 1);
+        src_ptr.write_volatile(3);
+        assert_eq!(frame_of(mapper, KERNEL_ADDR), frame);
+        assert_eq!(dst_ptr.read_volatile(), 2);
+    }
+}
+
+#[test_case]
+fn forked_address_spaces_diverge_after_write() {
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let ptr = USER_ADDR as *mut u64;
+
+    let mut parent = AddressSpace::new().expect("no frame for level 4 table");
+    parent
+        .map_user_page(page, PageTableFlags::WRITABLE)
+        .expect("map_user_page failed");
+    unsafe {
+        parent.activate();
+        ptr.write_volatile(1);
+    }
+
+    let child = parent.fork().expect("fork failed");
+    unsafe {
+        child.activate();
+        assert_eq!(ptr.read_volatile(), 1);
+        ptr.write_volatile(2);
+        assert_eq!(ptr.read_volatile(), 2);
+
+        parent.activate();
+        assert_eq!(ptr.read_volatile(), 1);
+        memory::activate_kernel_address_space();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,20 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!(
+    "device_not_available::use_fpu_with_task_switched",
+    "EXCEPTION: DEVICE NOT AVAILABLE",
+    use_fpu_with_task_switched
+);
+
+// CR0.TS 置位后执行 x87 指令会引起 #NM
+fn use_fpu_with_task_switched() {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    unsafe {
+        Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED));
+        asm!("fninit");
+    }
+}
@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!(
+    "divide_error::divide_by_zero",
+    "EXCEPTION: DIVIDE ERROR",
+    divide_by_zero
+);
+
+// 用汇编做除法, 避免编译器在编译期检查出除零
+fn divide_by_zero() {
+    unsafe {
+        asm!(
+            "xor edx, edx",
+            "xor ecx, ecx",
+            "div rcx",
+            inout("rax") 1u64 => _,
+            out("rcx") _,
+            out("rdx") _,
+        );
+    }
+}
@@ -0,0 +1,203 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::allocator;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use blog_os::task::executor::{Executor, STARVATION_LIMIT};
+use blog_os::task::{self, Priority, Task};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future::poll_fn;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[test_case]
+fn every_spawned_task_runs() {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    let first = executor.spawn(Task::new(async {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    }));
+    let second = executor.spawn(Task::new(async {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    }));
+    assert_ne!(first.id(), second.id());
+
+    executor.run_task();
+    assert_eq!(RUNS.load(Ordering::SeqCst), 2);
+    assert!(first.is_finished());
+    assert!(second.is_finished());
+}
+
+#[test_case]
+fn join_handle_yields_output() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let answer = executor.spawn(Task::new(async { 6 * 7 }));
+    executor.spawn(Task::new(async move {
+        assert_eq!(answer.await, 42);
+        DONE.store(true, Ordering::SeqCst);
+    }));
+
+    while !DONE.load(Ordering::SeqCst) {
+        executor.run_task();
+    }
+}
+
+#[test_case]
+fn spawner_adds_tasks_while_running() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(async move {
+        let inner = spawner.spawn(Task::new(async { "inner" }));
+        assert_eq!(inner.await, "inner");
+        DONE.store(true, Ordering::SeqCst);
+    }));
+
+    while !DONE.load(Ordering::SeqCst) {
+        executor.run_task();
+    }
+}
+
+#[test_case]
+fn global_spawn() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    task::set_global_spawner(executor.spawner());
+    let handle = task::spawn(Task::new(async {
+        DONE.store(true, Ordering::SeqCst);
+        1
+    }));
+    assert!(!handle.is_finished());
+
+    while !DONE.load(Ordering::SeqCst) {
+        executor.run_task();
+    }
+    assert!(handle.is_finished());
+}
+
+#[test_case]
+fn global_spawner_is_replaced() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let old = Executor::new();
+    task::set_global_spawner(old.spawner());
+    let spawner = old.spawner();
+    drop(old);
+    assert!(spawner.is_closed());
+
+    // 替换掉已经 drop 的 executor 的 spawner
+    let mut executor = Executor::new();
+    let previous = task::set_global_spawner(executor.spawner()).expect("no previous spawner");
+    assert!(previous.is_closed());
+    assert!(!executor.spawner().is_closed());
+    let handle = task::spawn(Task::new(async {
+        DONE.store(true, Ordering::SeqCst);
+    }));
+
+    while !DONE.load(Ordering::SeqCst) {
+        executor.run_task();
+    }
+    assert!(handle.is_finished());
+}
+
+static ORDER: AtomicUsize = AtomicUsize::new(0);
+
+// 记录任务在第几个运行
+async fn record(position: &'static AtomicUsize) {
+    position.store(ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+}
+
+#[test_case]
+fn higher_priority_runs_first() {
+    static BACKGROUND: AtomicUsize = AtomicUsize::new(0);
+    static INTERACTIVE: AtomicUsize = AtomicUsize::new(0);
+    static INTERRUPT: AtomicUsize = AtomicUsize::new(0);
+    ORDER.store(0, Ordering::SeqCst);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(record(&BACKGROUND)).with_priority(Priority::Background));
+    executor.spawn(Task::new(record(&INTERACTIVE)));
+    executor.spawn(Task::new(record(&INTERRUPT)).with_priority(Priority::Interrupt));
+    executor.run_task();
+
+    assert_eq!(INTERRUPT.load(Ordering::SeqCst), 0);
+    assert_eq!(INTERACTIVE.load(Ordering::SeqCst), 1);
+    assert_eq!(BACKGROUND.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn lower_priority_does_not_starve() {
+    static BACKGROUND: AtomicUsize = AtomicUsize::new(0);
+    static INTERACTIVE: AtomicUsize = AtomicUsize::new(0);
+    ORDER.store(0, Ordering::SeqCst);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(record(&BACKGROUND)).with_priority(Priority::Background));
+    for _ in 0..2 * STARVATION_LIMIT {
+        executor.spawn(Task::new(record(&INTERACTIVE)));
+    }
+    executor.run_task();
+
+    assert_eq!(BACKGROUND.load(Ordering::SeqCst), STARVATION_LIMIT);
+    assert_eq!(ORDER.load(Ordering::SeqCst), 2 * STARVATION_LIMIT + 1);
+}
+
+#[test_case]
+fn starvation_skips_finished_tasks() {
+    static BACKGROUND: AtomicUsize = AtomicUsize::new(0);
+    static INTERACTIVE: AtomicUsize = AtomicUsize::new(0);
+    static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+    ORDER.store(0, Ordering::SeqCst);
+
+    // 完成之后再唤醒, 它的 ID 留在 Background 队列的最前面
+    let mut executor = Executor::new();
+    executor.spawn(
+        Task::new(poll_fn(|cx| {
+            *WAKER.lock() = Some(cx.waker().clone());
+            Poll::Ready(())
+        }))
+        .with_priority(Priority::Background),
+    );
+    executor.run_task();
+    WAKER.lock().take().unwrap().wake();
+
+    executor.spawn(Task::new(record(&BACKGROUND)).with_priority(Priority::Background));
+    for _ in 0..2 * STARVATION_LIMIT {
+        executor.spawn(Task::new(record(&INTERACTIVE)));
+    }
+    executor.run_task();
+
+    assert_eq!(BACKGROUND.load(Ordering::SeqCst), STARVATION_LIMIT);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,17 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!(
+    "general_protection_fault::load_invalid_selector",
+    "EXCEPTION: GENERAL PROTECTION FAULT\nError Code: SelectorErrorCode { external: false, table: Gdt, index: 16 }",
+    load_invalid_selector
+);
+
+// 选择子 0x80 指向 GDT 的第 16 项, 超出了 GDT 的范围
+fn load_invalid_selector() {
+    unsafe {
+        asm!("mov ds, {0:x}", in(reg) 0x80u16);
+    }
+}
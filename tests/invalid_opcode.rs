@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!(
+    "invalid_opcode::execute_ud2",
+    "EXCEPTION: INVALID OPCODE",
+    execute_ud2
+);
+
+fn execute_ud2() {
+    unsafe {
+        asm!("ud2");
+    }
+}
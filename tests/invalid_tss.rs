@@ -0,0 +1,17 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{gdt, interrupts};
+
+blog_os::exception_test!(
+    "invalid_tss::raise_with_error_code",
+    "EXCEPTION: INVALID TSS\nError Code: SelectorErrorCode { external: false, table: Gdt, index: 5 }",
+    raise_with_error_code
+);
+
+// #TS 来自硬件任务切换和 TSS 中的栈选择子, 64 位模式下没有任务切换; 按 CPU 的方式压入栈帧和
+// TSS 的选择子 (GDT 的第 5 项) 作为错误码, 直接进入 handler
+fn raise_with_error_code() {
+    let tss = gdt::selectors().tss_selector;
+    unsafe { interrupts::raise_with_error_code(10, u64::from(tss.0)) };
+}
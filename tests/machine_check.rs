@@ -0,0 +1,13 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!("machine_check::raise_with_int", "EXCEPTION: MACHINE CHECK", raise_with_int);
+
+// #MC 由硬件错误引起, 软件无法制造; 用 int 18 直接进入 handler
+fn raise_with_int() {
+    unsafe {
+        asm!("int 18");
+    }
+}
@@ -0,0 +1,15 @@
+#![no_std]
+#![no_main]
+
+// 内核栈溢出时, page fault 在自己的 IST 栈上处理并报告为 StackGuard, 而不是变成 double fault
+blog_os::exception_test!(
+    "page_fault_stack::stack_overflow",
+    "EXCEPTION: PAGE FAULT\nKind: StackGuard",
+    stack_overflow
+);
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+
+use blog_os::interrupts;
+
+blog_os::exception_test!(
+    "security_exception::raise_with_error_code",
+    "EXCEPTION: SECURITY EXCEPTION\nError Code: 0x1",
+    raise_with_error_code
+);
+
+// #SX 只在 SVM 的 INIT 重定向下出现; 按 CPU 的方式压入栈帧和错误码 (1 表示被重定向的 INIT),
+// 直接进入 handler
+fn raise_with_error_code() {
+    unsafe { interrupts::raise_with_error_code(30, 1) };
+}
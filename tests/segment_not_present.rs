@@ -0,0 +1,17 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!(
+    "segment_not_present::int_to_missing_gate",
+    "EXCEPTION: SEGMENT NOT PRESENT\nError Code: SelectorErrorCode { external: false, table: Idt, index: 128 }",
+    int_to_missing_gate
+);
+
+// IDT 中没有注册 0x80 号中断, 它的门描述符 P 位为 0, int 0x80 会引起 #NP(0x80 << 3 | IDT)
+fn int_to_missing_gate() {
+    unsafe {
+        asm!("int 0x80");
+    }
+}
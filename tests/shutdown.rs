@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+// 用 scripts/test-shutdown.sh 运行: ACPI 关机后 QEMU 以状态 0 退出, 而 bootimage 的测试模式会把 0 当作失败.
+// 失败时 panic 通过 isa-debug-exit 报告, shutdown 本身失败则停机直到脚本超时.
+
+extern crate alloc;
+
+use blog_os::allocator;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use blog_os::{acpi, exit_qemu, power, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("shutdown::acpi_power_off...\t");
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    let tables = acpi::init().expect("ACPI tables not found");
+    let fadt = tables.fadt.expect("no FADT");
+    assert_ne!(fadt.pm1a_control_block, 0);
+
+    // 此后无法再输出结果, 成功与否由 QEMU 的退出状态决定
+    serial_println!("[powering off]");
+    power::shutdown();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop();
+}
@@ -0,0 +1,14 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!("simd_floating_point::raise_with_int", "EXCEPTION: SIMD FLOATING POINT", raise_with_int);
+
+// 内核 target 关闭了 SSE (x86_64-blog_os.json 中的 -sse), 也没有设置 CR4.OSXMMEXCPT,
+// 所以用 int 19 直接进入 handler
+fn raise_with_int() {
+    unsafe {
+        asm!("int 19");
+    }
+}
@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::interrupts::apic;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use blog_os::{acpi, allocator, percpu, smp};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+
+    acpi::init().expect("ACPI tables not found");
+    apic::init(&mut mapper).expect("APIC initialization failed");
+    let count = smp::init(&mut mapper).expect("SMP initialization failed");
+    STARTED.store(count, Ordering::SeqCst);
+
+    test_main();
+
+    loop {}
+}
+
+#[test_case]
+fn every_cpu_online() {
+    let madt = acpi::tables().unwrap().madt.as_ref().unwrap();
+    let enabled = madt.processors.iter().filter(|cpu| cpu.enabled).count();
+    // Cargo.toml 中的 test-args 使用 -smp 4
+    assert_eq!(enabled, 4);
+    assert_eq!(STARTED.load(Ordering::SeqCst), enabled);
+    assert_eq!(smp::online_cpus(), enabled);
+}
+
+#[test_case]
+fn boot_cpu_data() {
+    let cpu = percpu::current();
+    assert_eq!(cpu.index(), 0);
+    assert_eq!(cpu.apic_id(), apic::id());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
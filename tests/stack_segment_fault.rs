@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use lazy_static::lazy_static;
+use x86_64::structures::gdt::{Descriptor, DescriptorFlags, GlobalDescriptorTable};
+
+blog_os::exception_test!(
+    "stack_segment_fault::load_not_present_stack_segment",
+    "EXCEPTION: STACK SEGMENT FAULT\nError Code: SelectorErrorCode { external: false, table: Gdt, index: 2 }",
+    load_not_present_stack_segment
+);
+
+lazy_static! {
+    // 第 1 项和内核的 GDT 相同, 所以加载之后 CS 依然有效; 第 2 项是一个不存在的数据段
+    static ref GDT: GlobalDescriptorTable = {
+        let mut gdt = GlobalDescriptorTable::new();
+        gdt.add_entry(Descriptor::kernel_code_segment());
+        let not_present = match Descriptor::kernel_data_segment() {
+            Descriptor::UserSegment(bits) => bits & !DescriptorFlags::PRESENT.bits(),
+            Descriptor::SystemSegment(..) => unreachable!(),
+        };
+        gdt.add_entry(Descriptor::UserSegment(not_present));
+        gdt
+    };
+}
+
+// 把不存在的段加载到 SS 一定会引起 #SS, 错误码是它的选择子 (长模式下不检查段界限,
+// 而非规范地址的栈访问在 QEMU 的 TCG 中可能变成 #GP)
+fn load_not_present_stack_segment() {
+    GDT.load();
+    unsafe {
+        asm!("mov ss, {0:x}", in(reg) 2u16 << 3);
+    }
+}
@@ -0,0 +1,145 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os::allocator;
+use blog_os::memory::{self, AddressSpace, BootInfoFrameAllocator};
+use blog_os::syscall::{self, SyscallError};
+use blog_os::usermode;
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// 一个内核没有使用的第四级页表 slot 中的地址
+const USER_ADDR: u64 = 0x_3200_0000_0000;
+const STACK_ADDR: u64 = 0x_3200_0001_0000;
+
+// 用户程序: 用错误的 fd 调用 write, 栈指针和调用前相同时以返回值退出, 否则以 99 退出
+global_asm!(
+    ".global syscall_program_start",
+    ".global syscall_program_end",
+    "syscall_program_start:",
+    "    mov r12, rsp",
+    "    mov eax, 0", // SYS_WRITE
+    "    mov edi, 7",
+    "    xor esi, esi",
+    "    xor edx, edx",
+    "    syscall",
+    "    mov rdi, rax",
+    "    cmp rsp, r12",
+    "    je 2f",
+    "    mov edi, 99",
+    "2:",
+    "    mov eax, 1", // SYS_EXIT
+    "    syscall",
+    "syscall_program_end:",
+);
+
+extern "C" {
+    fn syscall_program_start();
+    fn syscall_program_end();
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+fn user_address_space(flags: PageTableFlags) -> AddressSpace {
+    let mut address_space = AddressSpace::new().expect("no frame for level 4 table");
+    address_space
+        .map_user_page(Page::containing_address(VirtAddr::new(USER_ADDR)), flags)
+        .expect("map_user_page failed");
+    address_space
+}
+
+#[test_case]
+fn write_from_user_buffer() {
+    let address_space = user_address_space(PageTableFlags::WRITABLE);
+    let text = b"syscall::write_from_user_buffer output\n";
+
+    unsafe {
+        address_space.activate();
+        core::ptr::copy_nonoverlapping(text.as_ptr(), USER_ADDR as *mut u8, text.len());
+    }
+    let args = [syscall::STDERR, USER_ADDR, text.len() as u64, 0, 0, 0];
+    let written = syscall::dispatch(syscall::SYS_WRITE, args);
+    // 跨过映射的页的末尾
+    let args = [syscall::STDERR, USER_ADDR + 4000, 200, 0, 0, 0];
+    let overflow = syscall::dispatch(syscall::SYS_WRITE, args);
+    unsafe { memory::activate_kernel_address_space() };
+
+    assert_eq!(written, text.len() as u64);
+    assert_eq!(overflow, SyscallError::BadAddress.as_return_value());
+}
+
+#[test_case]
+fn write_rejects_invalid_utf8() {
+    let address_space = user_address_space(PageTableFlags::WRITABLE);
+
+    unsafe {
+        address_space.activate();
+        (USER_ADDR as *mut u8).write_volatile(0xff);
+    }
+    let result = syscall::dispatch(syscall::SYS_WRITE, [syscall::STDOUT, USER_ADDR, 1, 0, 0, 0]);
+    unsafe { memory::activate_kernel_address_space() };
+
+    assert_eq!(result, SyscallError::InvalidArgument.as_return_value());
+}
+
+#[test_case]
+fn write_rejects_kernel_buffer() {
+    // 内核的页不能被用户程序读取
+    let text = "kernel memory";
+    let args = [syscall::STDOUT, text.as_ptr() as u64, text.len() as u64, 0, 0, 0];
+    assert_eq!(
+        syscall::dispatch(syscall::SYS_WRITE, args),
+        SyscallError::BadAddress.as_return_value()
+    );
+}
+
+#[test_case]
+fn syscall_from_ring_3() {
+    let mut address_space = user_address_space(PageTableFlags::WRITABLE);
+    address_space
+        .map_user_page(
+            Page::containing_address(VirtAddr::new(STACK_ADDR)),
+            PageTableFlags::WRITABLE,
+        )
+        .expect("map_user_page failed");
+
+    let start = syscall_program_start as *const () as usize;
+    let len = syscall_program_end as *const () as usize - start;
+    let code = unsafe {
+        address_space.activate();
+        core::ptr::copy_nonoverlapping(start as *const u8, USER_ADDR as *mut u8, len);
+        usermode::run(VirtAddr::new(USER_ADDR), VirtAddr::new(STACK_ADDR + 4096))
+    };
+    unsafe { memory::activate_kernel_address_space() };
+
+    assert_eq!(
+        code as u64,
+        SyscallError::BadFileDescriptor.as_return_value()
+    );
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::allocator;
+use blog_os::memory::{self, BootInfoFrameAllocator};
+use blog_os::task::executor::Executor;
+use blog_os::task::timer::{self, interval, sleep, sleep_until};
+use blog_os::task::Task;
+use blog_os::time::Instant;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Context;
+use core::time::Duration;
+use futures_util::stream::StreamExt;
+use futures_util::task::noop_waker;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+static DONE: AtomicBool = AtomicBool::new(false);
+
+/// 运行 executor, 直到任务把 DONE 置位
+fn run_until_done(task: Task) {
+    DONE.store(false, Ordering::SeqCst);
+    let mut executor = Executor::new();
+    executor.spawn(task);
+    while !DONE.load(Ordering::SeqCst) {
+        executor.run_task();
+    }
+}
+
+#[test_case]
+fn sleep_waits() {
+    run_until_done(Task::new(async {
+        let start = Instant::now();
+        sleep(Duration::from_millis(50)).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        DONE.store(true, Ordering::SeqCst);
+    }));
+}
+
+#[test_case]
+fn sleep_until_past_deadline_is_ready() {
+    run_until_done(Task::new(async {
+        let start = Instant::now();
+        sleep_until(start).await;
+        sleep(Duration::from_secs(0)).await;
+        assert!(start.elapsed() < Duration::from_millis(10));
+        DONE.store(true, Ordering::SeqCst);
+    }));
+}
+
+#[test_case]
+fn interval_ticks_periodically() {
+    run_until_done(Task::new(async {
+        let start = Instant::now();
+        let ticks: Vec<Instant> = interval(Duration::from_millis(10)).take(3).collect().await;
+        assert_eq!(ticks[1] - ticks[0], Duration::from_millis(10));
+        assert_eq!(ticks[2] - ticks[1], Duration::from_millis(10));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        DONE.store(true, Ordering::SeqCst);
+    }));
+}
+
+// 重复 poll 不会重复注册, drop 之后表项被删除
+#[test_case]
+fn sleep_registers_once_and_cancels_on_drop() {
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let before = timer::pending();
+
+    let mut sleep = sleep(Duration::from_secs(60));
+    for _ in 0..3 {
+        assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending());
+    }
+    assert_eq!(timer::pending(), before + 1);
+
+    drop(sleep);
+    assert_eq!(timer::pending(), before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
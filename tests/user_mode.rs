@@ -0,0 +1,211 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use blog_os::memory::{self, AddressSpace, BootInfoFrameAllocator};
+use blog_os::{allocator, gdt, interrupts, percpu, usermode};
+use bootloader::{entry_point, BootInfo};
+use core::arch::{asm, global_asm};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// 一个内核没有使用的第四级页表 slot 中的地址
+const CODE_ADDR: u64 = 0x_3200_0000_0000;
+const STACK_ADDR: u64 = 0x_3200_0001_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+// 用户程序: 向串口输出一行, 再以 42 退出. 只使用相对寻址, 可以被复制到任意地址运行
+global_asm!(
+    ".global user_program_start",
+    ".global user_program_end",
+    "user_program_start:",
+    "    mov eax, 0", // SYS_WRITE
+    "    mov edi, 2", // STDERR
+    "    lea rsi, [rip + user_program_message]",
+    "    mov edx, user_program_end - user_program_message",
+    "    syscall",
+    "    mov eax, 1", // SYS_EXIT
+    "    mov edi, 42",
+    "    syscall",
+    "    ud2",
+    "user_program_message:",
+    "    .ascii \"hello from ring 3\\n\"",
+    "user_program_end:",
+);
+
+// 忙等一段时间再退出, 期间的定时器中断发生在 ring 3
+global_asm!(
+    ".global spin_program_start",
+    ".global spin_program_end",
+    "spin_program_start:",
+    "    mov ecx, 50000000",
+    "2:",
+    "    dec ecx",
+    "    jnz 2b",
+    "    mov eax, 1", // SYS_EXIT
+    "    xor edi, edi",
+    "    syscall",
+    "spin_program_end:",
+);
+
+// 加载 GS 段 (GS 基址变成 0) 之后进入内核: 先是 yield 系统调用, 再在忙等时被定时器中断.
+// 最后通过 GS 读取自己的一个常量作为退出码, GS 基址不再是 0 的话就会 page fault
+global_asm!(
+    ".global gs_program_start",
+    ".global gs_program_end",
+    "gs_program_start:",
+    "    mov eax, ss",
+    "    mov gs, eax",
+    "    mov eax, 2", // SYS_YIELD
+    "    syscall",
+    "    mov ecx, 50000000",
+    "2:",
+    "    dec ecx",
+    "    jnz 2b",
+    "    lea rbx, [rip + gs_program_value]",
+    "    mov edi, gs:[rbx]",
+    "    mov eax, 1", // SYS_EXIT
+    "    syscall",
+    "gs_program_value:",
+    "    .long 0x6b5",
+    "gs_program_end:",
+);
+
+extern "C" {
+    fn user_program_start();
+    fn user_program_end();
+    fn spin_program_start();
+    fn spin_program_end();
+    fn gs_program_start();
+    fn gs_program_end();
+}
+
+/// 把用户程序复制到新地址空间的代码页中, 并映射一个栈页
+fn load_user_program() -> AddressSpace {
+    load_program(user_program_start, user_program_end)
+}
+
+fn load_program(start: unsafe extern "C" fn(), end: unsafe extern "C" fn()) -> AddressSpace {
+    let mut address_space = AddressSpace::new().expect("no frame for level 4 table");
+    for addr in [CODE_ADDR, STACK_ADDR] {
+        address_space
+            .map_user_page(
+                Page::containing_address(VirtAddr::new(addr)),
+                PageTableFlags::WRITABLE,
+            )
+            .expect("map_user_page failed");
+    }
+
+    let start = start as *const () as usize;
+    let len = end as *const () as usize - start;
+    unsafe {
+        address_space.activate();
+        core::ptr::copy_nonoverlapping(start as *const u8, CODE_ADDR as *mut u8, len);
+    }
+    address_space
+}
+
+#[test_case]
+fn run_user_program() {
+    let address_space = load_user_program();
+    let code = unsafe { usermode::run(VirtAddr::new(CODE_ADDR), VirtAddr::new(STACK_ADDR + 4096)) };
+    unsafe { memory::activate_kernel_address_space() };
+    drop(address_space);
+
+    assert_eq!(code, 42);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn run_user_program_twice() {
+    for _ in 0..2 {
+        let _address_space = load_user_program();
+        let code =
+            unsafe { usermode::run(VirtAddr::new(CODE_ADDR), VirtAddr::new(STACK_ADDR + 4096)) };
+        unsafe { memory::activate_kernel_address_space() };
+        assert_eq!(code, 42);
+    }
+}
+
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 4;
+// 新的 privilege stack 的范围, 供中断 handler 检查
+static PRIVILEGE_STACK_START: AtomicU64 = AtomicU64::new(0);
+static INTERRUPTED_ON_STACK: AtomicBool = AtomicBool::new(false);
+
+fn check_interrupt_stack() {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+    let start = PRIVILEGE_STACK_START.load(Ordering::SeqCst);
+    if (start..start + PRIVILEGE_STACK_SIZE as u64).contains(&rsp) {
+        INTERRUPTED_ON_STACK.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn interrupt_uses_privilege_stack() {
+    let stack = vec![0u8; PRIVILEGE_STACK_SIZE].into_boxed_slice();
+    let stack_start = VirtAddr::from_ptr(stack.as_ptr());
+    PRIVILEGE_STACK_START.store(stack_start.as_u64(), Ordering::SeqCst);
+
+    let old = gdt::privilege_stack(0);
+    unsafe { gdt::set_privilege_stack(0, stack_start + PRIVILEGE_STACK_SIZE) };
+    interrupts::register_irq(0, check_interrupt_stack).expect("registering handler failed");
+
+    let _address_space = load_program(spin_program_start, spin_program_end);
+    let code = unsafe { usermode::run(VirtAddr::new(CODE_ADDR), VirtAddr::new(STACK_ADDR + 4096)) };
+    unsafe { memory::activate_kernel_address_space() };
+
+    interrupts::unregister_irq(0, check_interrupt_stack);
+    unsafe { gdt::set_privilege_stack(0, old) };
+    assert_eq!(code, 0);
+    assert!(INTERRUPTED_ON_STACK.load(Ordering::SeqCst));
+}
+
+static INTERRUPTED_CPU: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn record_current_cpu() {
+    INTERRUPTED_CPU.store(percpu::current().index() as u64, Ordering::SeqCst);
+}
+
+#[test_case]
+fn user_gs_does_not_affect_per_cpu_data() {
+    interrupts::register_irq(0, record_current_cpu).expect("registering handler failed");
+
+    let _address_space = load_program(gs_program_start, gs_program_end);
+    let code = unsafe { usermode::run(VirtAddr::new(CODE_ADDR), VirtAddr::new(STACK_ADDR + 4096)) };
+    unsafe { memory::activate_kernel_address_space() };
+
+    interrupts::unregister_irq(0, record_current_cpu);
+    assert_eq!(code, 0x6b5);
+    assert_eq!(INTERRUPTED_CPU.load(Ordering::SeqCst), 0);
+    let cpu = percpu::current();
+    assert_eq!(cpu.index(), 0);
+    assert_eq!(GsBase::read(), VirtAddr::from_ptr(cpu));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
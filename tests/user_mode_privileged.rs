@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+
+use blog_os::memory::{self, AddressSpace, BootInfoFrameAllocator};
+use blog_os::{allocator, exit_qemu, serial_print, serial_println, usermode, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// 一个内核没有使用的第四级页表 slot 中的地址
+const CODE_ADDR: u64 = 0x_3200_0000_0000;
+const STACK_ADDR: u64 = 0x_3200_0001_0000;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // hlt 在 ring 3 中是特权指令, 错误码为 0
+    blog_os::test_expected_panic_handler(
+        info,
+        "EXCEPTION: GENERAL PROTECTION FAULT\nError Code: SelectorErrorCode { external: false, table: Gdt, index: 0 }",
+    )
+}
+
+entry_point!(main);
+
+// 集成测试入口, 异常 handler 会 panic, 所以不使用测试框架 (在 Cargo.toml 中的 [[test]] 中关闭)
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("user_mode_privileged::hlt_in_ring_3...\t");
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+
+    let mut address_space = AddressSpace::new().expect("no frame for level 4 table");
+    for addr in [CODE_ADDR, STACK_ADDR] {
+        address_space
+            .map_user_page(
+                Page::containing_address(VirtAddr::new(addr)),
+                PageTableFlags::WRITABLE,
+            )
+            .expect("map_user_page failed");
+    }
+
+    unsafe {
+        address_space.activate();
+        // hlt
+        (CODE_ADDR as *mut u8).write_volatile(0xf4);
+        usermode::run(VirtAddr::new(CODE_ADDR), VirtAddr::new(STACK_ADDR + 4096));
+    }
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
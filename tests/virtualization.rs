@@ -0,0 +1,13 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!("virtualization::raise_with_int", "EXCEPTION: VIRTUALIZATION", raise_with_int);
+
+// #VE 只在 VMX non-root 模式下由 EPT violation 引起; 用 int 20 直接进入 handler
+fn raise_with_int() {
+    unsafe {
+        asm!("int 20");
+    }
+}
@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+
+use blog_os::interrupts;
+
+blog_os::exception_test!(
+    "vmm_communication::raise_with_error_code",
+    "EXCEPTION: VMM COMMUNICATION\nError Code: 0x72",
+    raise_with_error_code
+);
+
+// #VC 只在 SEV-ES 虚拟机中出现; 按 CPU 的方式压入栈帧和错误码 (0x72 是 cpuid 的 exit code),
+// 直接进入 handler
+fn raise_with_error_code() {
+    unsafe { interrupts::raise_with_error_code(29, 0x72) };
+}
@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+blog_os::exception_test!(
+    "x87_floating_point::unmasked_divide_by_zero",
+    "EXCEPTION: x87 FLOATING POINT",
+    unmasked_divide_by_zero
+);
+
+// CR0.NE 置位时, 未屏蔽的 x87 异常在下一条等待型浮点指令 (fwait) 处报告为 #MF
+fn unmasked_divide_by_zero() {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.insert(Cr0Flags::NUMERIC_ERROR);
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+        });
+        asm!(
+            "fninit",
+            // 控制字 0x37b: 默认的 0x37f 去掉 ZM 位, 不再屏蔽除零
+            "push 0x37b",
+            "fldcw [rsp]",
+            "add rsp, 8",
+            "fld1",
+            "fldz",
+            "fdivp st(1), st",
+            "fwait",
+        );
+    }
+}